@@ -0,0 +1,19 @@
+use std::process::Command;
+
+/// Set `REL4_CLI_GIT_HASH` to the commit the CLI is built from, for `--version`
+fn main() {
+    let hash = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=REL4_CLI_GIT_HASH={}", hash);
+
+    // HEAD names the branch, whose ref moves on every commit
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    if let Some(reference) = std::fs::read_to_string(".git/HEAD").ok().and_then(|head| head.strip_prefix("ref: ").map(|r| r.trim().to_string())) {
+        println!("cargo:rerun-if-changed=.git/{}", reference);
+    }
+}
@@ -0,0 +1,87 @@
+use std::path::Path;
+
+use crate::exec;
+use crate::install::{work_dir, LIBSEL4_DIR, LINUX_KIT_DIR, REL4_KERNEL_DIR, SEL4_KERNEL_DIR};
+
+/// Remove the working directories left behind by `install` under `tmp_dir`.
+/// With `--dry-run` only list what would be deleted
+pub(crate) fn clean(tmp_dir: &str) -> anyhow::Result<()> {
+    let mut reclaimed = 0;
+    for name in [REL4_KERNEL_DIR, SEL4_KERNEL_DIR, LINUX_KIT_DIR, LIBSEL4_DIR] {
+        let dir = work_dir(tmp_dir, name);
+        let path = Path::new(&dir);
+        if !path.exists() {
+            continue;
+        }
+
+        let size = dir_size(path)?;
+        reclaimed += size;
+        if exec::dry_run() {
+            println!("Would remove {} ({})", dir, format_size(size));
+        } else {
+            std::fs::remove_dir_all(path)?;
+            println!("Removed {} ({})", dir, format_size(size));
+        }
+    }
+
+    if exec::dry_run() {
+        println!("{} would be reclaimed", format_size(reclaimed));
+    } else {
+        println!("{} reclaimed", format_size(reclaimed));
+    }
+    Ok(())
+}
+
+/// Total size of all files under path, symlinks are not followed
+fn dir_size(path: &Path) -> anyhow::Result<u64> {
+    let metadata = std::fs::symlink_metadata(path)?;
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+
+    let mut size = 0;
+    for entry in std::fs::read_dir(path)? {
+        size += dir_size(&entry?.path())?;
+    }
+    Ok(size)
+}
+
+/// Human readable size, e.g. `1.5 GiB`
+fn format_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = size as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", size, UNITS[unit])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clean_removes_work_dirs() {
+        let tmp_dir = std::env::temp_dir().join(format!("rel4-cli-clean-{}", std::process::id()));
+        let tmp_dir = tmp_dir.to_str().unwrap();
+        let names = [REL4_KERNEL_DIR, SEL4_KERNEL_DIR, LINUX_KIT_DIR, LIBSEL4_DIR];
+        for name in names {
+            let dir = work_dir(tmp_dir, name);
+            std::fs::create_dir_all(Path::new(&dir).join("build")).unwrap();
+            std::fs::write(Path::new(&dir).join("build/kernel.elf"), b"elf").unwrap();
+        }
+
+        clean(tmp_dir).unwrap();
+
+        for name in names {
+            assert!(!Path::new(&work_dir(tmp_dir, name)).exists());
+        }
+        std::fs::remove_dir_all(tmp_dir).unwrap();
+    }
+}
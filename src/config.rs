@@ -0,0 +1,254 @@
+use clap::parser::ValueSource;
+use clap::{ArgMatches, Parser};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+use crate::error::InstallError;
+use crate::install::{Benchmark, HomeFixup, KernelOptions, Profile};
+use crate::platform::{Platform, RiscvExt};
+
+/// `install kernel` options loaded from a TOML file, every field is optional
+/// and named the same as its `KernelOptions` counterpart
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct KernelConfig {
+    /// One platform or a list of them
+    #[serde(rename = "platform", alias = "platforms", default, deserialize_with = "one_or_many")]
+    pub platforms: Option<Vec<Platform>>,
+    pub mcs: Option<bool>,
+    pub nofastpath: Option<bool>,
+    pub bin: Option<bool>,
+    pub kernel_name: Option<String>,
+    pub sel4_prefix: Option<String>,
+    pub tmp_dir: Option<String>,
+    pub local: Option<String>,
+    pub local_sel4: Option<String>,
+    pub branch: Option<String>,
+    pub rel4_commit: Option<String>,
+    pub sel4_c_impl_branch: Option<String>,
+    pub sel4_c_impl_commit: Option<String>,
+    pub force: Option<bool>,
+    pub force_clone: Option<bool>,
+    pub force_loader: Option<bool>,
+    pub clone_retries: Option<u32>,
+    pub sel4_baseline: Option<String>,
+    pub jobs: Option<usize>,
+    pub kernel_toolchain: Option<String>,
+    pub loader_toolchain: Option<String>,
+    pub cross_compiler_prefix: Option<String>,
+    pub arm_cpu: Option<String>,
+    pub riscv_ext: Option<RiscvExt>,
+    pub hypervisor: Option<bool>,
+    pub smp: Option<u32>,
+    pub depth: Option<u32>,
+    pub full_clone: Option<bool>,
+    pub offline: Option<bool>,
+    pub home_fixup: Option<HomeFixup>,
+    pub clean_build: Option<bool>,
+    pub git_mirror: Option<String>,
+    pub rel4_repo: Option<String>,
+    pub sel4_repo: Option<String>,
+    pub loader_repo: Option<String>,
+    pub loader_rev: Option<String>,
+    pub skip_tool_check: Option<bool>,
+    pub auto_toolchain: Option<bool>,
+    pub min_free_space: Option<u64>,
+    pub cmake_arg: Option<Vec<String>>,
+    pub xtask_arg: Option<Vec<String>>,
+    pub keep_failed_build: Option<bool>,
+    pub sel4_config: Option<std::path::PathBuf>,
+    pub profile: Option<Profile>,
+    pub patch: Option<Vec<std::path::PathBuf>>,
+    pub benchmark: Option<Benchmark>,
+    pub kernel_debug: Option<bool>,
+    pub kernel_release: Option<bool>,
+    pub continue_on_error: Option<bool>,
+    pub sequential: Option<bool>,
+    pub expect_sha256: Option<String>,
+    pub no_loader: Option<bool>,
+    pub payload_tool_only: Option<bool>,
+    pub loader_only: Option<bool>,
+    pub cargo_target_dir: Option<std::path::PathBuf>,
+    pub cargo_home: Option<std::path::PathBuf>,
+    pub with_payload: Option<std::path::PathBuf>,
+    pub image: Option<std::path::PathBuf>,
+}
+
+/// The project config file `install kernel` picks up without `--config-file`
+const DISCOVERED_CONFIG: &str = "rel4-cli.toml";
+
+impl KernelConfig {
+    /// The `rel4-cli.toml` in the current dir or the closest of its parents, like cargo finds `Cargo.toml`
+    pub(crate) fn discover() -> Option<PathBuf> {
+        let cwd = std::env::current_dir().ok()?;
+        cwd.ancestors().map(|dir| dir.join(DISCOVERED_CONFIG)).find(|path| path.is_file())
+    }
+
+    pub(crate) fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| InstallError::ConfigFile { path: path.to_path_buf(), reason: err.to_string() })?;
+        toml::from_str(&content)
+            .map_err(|err| InstallError::ConfigFile { path: path.to_path_buf(), reason: err.to_string() }.into())
+    }
+
+    /// Merge the config into the options, options given on the command line win
+    pub(crate) fn merge(self, opts: &mut KernelOptions, matches: Option<&ArgMatches>) {
+        macro_rules! merge {
+            ($($field:ident),*) => {
+                $(
+                    if let Some(value) = self.$field {
+                        if !from_command_line(matches, stringify!($field)) {
+                            opts.$field = value;
+                        }
+                    }
+                )*
+            };
+        }
+        macro_rules! merge_optional {
+            ($($field:ident),*) => {
+                $(
+                    if self.$field.is_some() && !from_command_line(matches, stringify!($field)) {
+                        opts.$field = self.$field;
+                    }
+                )*
+            };
+        }
+
+        merge!(platforms, mcs, nofastpath, bin, kernel_name, sel4_prefix, tmp_dir, branch, force, force_clone, force_loader, clone_retries, kernel_toolchain, loader_toolchain, hypervisor, smp,
+               depth, full_clone, offline, home_fixup, clean_build,
+               rel4_repo, sel4_repo, loader_repo, loader_rev, skip_tool_check, auto_toolchain, keep_failed_build, cmake_arg, xtask_arg, profile, patch, benchmark, kernel_debug, kernel_release, continue_on_error, sequential, no_loader, payload_tool_only, loader_only);
+        merge_optional!(local, local_sel4, rel4_commit, sel4_c_impl_branch, sel4_c_impl_commit, sel4_baseline, jobs, cross_compiler_prefix, arm_cpu, riscv_ext, git_mirror, expect_sha256, min_free_space, sel4_config,
+                        cargo_target_dir, cargo_home, with_payload, image);
+    }
+}
+
+/// Options that cannot be given together, as clap declares them on `KernelOptions`
+const CONFLICTS: &[(&str, &[&str])] = &[
+    ("local", &["branch", "rel4_commit", "sel4_c_impl_branch", "sel4_c_impl_commit", "depth", "full_clone", "force_clone", "rel4_repo", "sel4_repo", "patch"]),
+    ("rel4_commit", &["branch"]),
+    ("sel4_c_impl_commit", &["sel4_c_impl_branch"]),
+    ("sel4_baseline", &["bin", "local", "local_sel4", "branch", "rel4_commit", "sel4_c_impl_branch", "sel4_c_impl_commit",
+                        "rel4_repo", "sel4_repo", "home_fixup", "patch", "xtask_arg", "profile", "sel4_config"]),
+    ("full_clone", &["depth"]),
+    ("kernel_debug", &["kernel_release"]),
+    ("no_loader", &["loader_only"]),
+    ("payload_tool_only", &["no_loader"]),
+];
+
+/// Check the ranges and conflicts clap checks on the command line again after merging, a config
+/// file bypasses them. An option left at its default counts as not given
+pub(crate) fn check_options(opts: &KernelOptions) -> Result<(), InstallError> {
+    for (name, value) in [("smp", opts.smp), ("depth", opts.depth), ("clone_retries", opts.clone_retries)] {
+        if value < 1 {
+            return Err(InstallError::Usage(format!("`{}` must be at least 1", name)));
+        }
+    }
+
+    let defaults = KernelOptions::try_parse_from(["kernel"]).map_err(|err| InstallError::Usage(err.to_string()))?;
+    let given = [
+        ("local", opts.local.is_some()),
+        ("local_sel4", opts.local_sel4.is_some()),
+        ("branch", opts.branch != defaults.branch),
+        ("rel4_commit", opts.rel4_commit.is_some()),
+        ("sel4_c_impl_branch", opts.sel4_c_impl_branch.is_some()),
+        ("sel4_c_impl_commit", opts.sel4_c_impl_commit.is_some()),
+        ("depth", opts.depth != defaults.depth),
+        ("full_clone", opts.full_clone),
+        ("force_clone", opts.force_clone),
+        ("rel4_repo", opts.rel4_repo != defaults.rel4_repo),
+        ("sel4_repo", opts.sel4_repo != defaults.sel4_repo),
+        ("patch", !opts.patch.is_empty()),
+        ("sel4_baseline", opts.sel4_baseline.is_some()),
+        ("bin", opts.bin),
+        ("home_fixup", opts.home_fixup != defaults.home_fixup),
+        ("xtask_arg", !opts.xtask_arg.is_empty()),
+        ("profile", opts.profile != defaults.profile),
+        ("sel4_config", opts.sel4_config.is_some()),
+        ("kernel_debug", opts.kernel_debug),
+        ("kernel_release", opts.kernel_release),
+        ("no_loader", opts.no_loader),
+        ("loader_only", opts.loader_only),
+        ("payload_tool_only", opts.payload_tool_only),
+    ];
+    let is_given = |name: &str| given.iter().any(|&(given_name, set)| given_name == name && set);
+    for (name, conflicts) in CONFLICTS {
+        if let Some(other) = conflicts.iter().find(|other| is_given(name) && is_given(other)) {
+            return Err(InstallError::Usage(format!("`{}` cannot be used with `{}`", name, other)));
+        }
+    }
+    if opts.image.is_some() && opts.with_payload.is_none() {
+        return Err(InstallError::Usage("`image` requires `with_payload`".to_string()));
+    }
+    Ok(())
+}
+
+/// A platform list given as a single platform, e.g. `platform = "spike"`, or as an array
+fn one_or_many<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<Platform>>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(Platform),
+        Many(Vec<Platform>),
+    }
+    Ok(Some(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(platform) => vec![platform],
+        OneOrMany::Many(platforms) => platforms,
+    }))
+}
+
+/// Whether the option was given explicitly rather than left to its default, an environment
+/// variable counts as explicit
+fn from_command_line(matches: Option<&ArgMatches>, id: &str) -> bool {
+    matches
+        .and_then(|matches| matches.value_source(id))
+        .is_some_and(|source| source != ValueSource::DefaultValue)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, FromArgMatches};
+
+    /// The `install kernel` options of args, merged with the config
+    fn merged(config: &str, args: &[&str]) -> KernelOptions {
+        let matches = KernelOptions::command().try_get_matches_from([&["kernel"], args].concat()).unwrap();
+        let mut opts = KernelOptions::from_arg_matches(&matches).unwrap();
+        toml::from_str::<KernelConfig>(config).unwrap().merge(&mut opts, Some(&matches));
+        opts
+    }
+
+    #[test]
+    fn merge_command_line_wins() {
+        let opts = merged("smp = 2\nmcs = true\nbranch = \"dev\"\nplatform = \"spike\"", &["--smp", "4"]);
+        assert_eq!(opts.smp, 4);
+        assert!(opts.mcs);
+        assert_eq!(opts.branch, "dev");
+        assert_eq!(opts.platforms, [Platform::Spike]);
+    }
+
+    #[test]
+    fn merge_default_values_are_overridden() {
+        let opts = merged("depth = 5\nkernel_name = \"rel4.elf\"", &["--depth", "1"]);
+        assert_eq!(opts.depth, 1);
+        assert_eq!(opts.kernel_name, "rel4.elf");
+        check_options(&opts).unwrap();
+    }
+
+    #[test]
+    fn check_options_rejects_out_of_range_values() {
+        for config in ["smp = 0", "depth = 0", "clone_retries = 0"] {
+            let err = check_options(&merged(config, &[])).unwrap_err();
+            assert!(matches!(err, InstallError::Usage(_)), "{}", config);
+            assert_eq!(err.exit_code(), 2);
+        }
+    }
+
+    #[test]
+    fn check_options_rejects_conflicts() {
+        let err = check_options(&merged("kernel_debug = true\nkernel_release = true", &[])).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid options: `kernel_debug` cannot be used with `kernel_release`");
+        let err = check_options(&merged("branch = \"dev\"", &["--local", "/tmp/rel4"])).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid options: `local` cannot be used with `branch`");
+        assert!(check_options(&merged("image = \"image.elf\"", &[])).is_err());
+    }
+}
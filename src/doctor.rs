@@ -0,0 +1,63 @@
+use clap::{Parser, ValueEnum};
+
+use crate::error::InstallError;
+use crate::exec;
+use crate::install::{installed_toolchains, tool_available, KERNEL_TOOLCHAIN, LOADER_TOOLCHAIN};
+use crate::platform::Platform;
+
+#[derive(Debug, Parser)]
+pub(crate) struct DoctorOptions {
+    /// Only check the cross compiler of this platform, all platforms are checked when unset
+    #[clap(short, long)]
+    pub platform: Option<Platform>,
+}
+
+/// Check the host tools needed by `install`, fails if any of them is missing
+pub(crate) fn doctor(opts: DoctorOptions) -> anyhow::Result<()> {
+    let mut tools: Vec<String> = ["git", "cmake", "ninja", "rustup"].map(String::from).to_vec();
+    match opts.platform {
+        Some(platform) => tools.push(platform.cross_compiler()),
+        None => {
+            for platform in Platform::value_variants() {
+                let compiler = platform.cross_compiler();
+                if !tools.contains(&compiler) {
+                    tools.push(compiler);
+                }
+            }
+        }
+    }
+
+    let mut missing = 0;
+    for tool in tools {
+        let found = tool_available(&tool);
+        report(&tool, found);
+        if !found {
+            missing += 1;
+        }
+    }
+
+    let installed = installed_toolchains();
+    for toolchain in [KERNEL_TOOLCHAIN, LOADER_TOOLCHAIN] {
+        let found = installed.iter().any(|installed| installed.starts_with(toolchain));
+        report(&format!("rust toolchain {}", toolchain), found);
+        if !found {
+            missing += 1;
+        }
+    }
+
+    if missing > 0 {
+        return Err(InstallError::ToolsMissing(missing).into());
+    }
+    Ok(())
+}
+
+fn report(name: &str, found: bool) {
+    // The marks only tell apart by color, words are used without it
+    let mark = match (found, exec::color()) {
+        (true, true) => exec::paint("✓", exec::GREEN),
+        (false, true) => exec::paint("✗", exec::RED),
+        (true, false) => format!("{:<7}", "ok"),
+        (false, false) => "missing".to_string(),
+    };
+    println!("{} {}", mark, name);
+}
@@ -0,0 +1,111 @@
+use std::path::PathBuf;
+
+/// Why an install failed, wrapped in `anyhow::Error` and recovered in `main`
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum InstallError {
+    #[error("git clone of {repo} failed after {attempts} attempts")]
+    CloneFailed { repo: String, attempts: u32 },
+    #[error("{repo} has no branch or tag {reference}")]
+    RefNotFound { repo: String, reference: String },
+    #[error("git fetch of {reference} in {dir} failed")]
+    FetchFailed { reference: String, dir: String },
+    #[error("Failed to reset {dir} to {reference}")]
+    ResetFailed { reference: String, dir: String },
+    #[error("Failed to checkout {reference} in {dir}, the clone is kept for inspection")]
+    CheckoutFailed { reference: String, dir: String },
+    #[error("Expected cloned kernel at {} but it is missing, the clone may have failed", .0.display())]
+    KernelMissing(PathBuf),
+    #[error("Invalid --local path {}: {reason}", path.display())]
+    InvalidLocal { path: PathBuf, reason: String },
+    #[error("Failed to apply patch {} in {dir}", patch.display())]
+    PatchFailed { patch: PathBuf, dir: String },
+    #[error("Kernel settings file {} does not exist", .0.display())]
+    SettingsMissing(PathBuf),
+    #[error("Offline mode needs an existing clone at {0}")]
+    CloneMissing(String),
+    #[error("Failed to configure {platform} with CMake")]
+    CmakeConfigure { platform: String },
+    #[error("Failed to {phase} with Ninja")]
+    NinjaBuild { phase: &'static str },
+    #[error("Failed to build {0}")]
+    BuildFailed(&'static str),
+    #[error("Failed to install {krate}, `{command}` {status}")]
+    CargoInstall { krate: &'static str, command: String, status: std::process::ExitStatus },
+    #[error("Unsupported platform: {0}")]
+    UnsupportedPlatform(String),
+    #[error("Hypervisor mode is not supported on platform {0}")]
+    HypervisorUnsupported(String),
+    #[error("RISC-V extensions {ext} are not supported on platform {platform}")]
+    RiscvExtUnsupported { ext: &'static str, platform: String },
+    #[error("'{0}' not found, please install it")]
+    ToolMissing(String),
+    #[error("{tool} not found on PATH; {hint}")]
+    ToolNotFound { tool: String, hint: &'static str },
+    #[error("Rust {}, fix it with `{}` or pass --auto-toolchain", problems.join(", "), commands.join("` and `"))]
+    ToolchainsMissing { problems: Vec<String>, commands: Vec<String> },
+    #[error("`rustup {0}` failed")]
+    ToolchainInstall(String),
+    #[error("Cross compiler '{compiler}' not found, install the toolchain for platform {platform}")]
+    CrossCompilerMissing { compiler: String, platform: String },
+    #[error("{0} required tools are missing")]
+    ToolsMissing(usize),
+    #[error("No {project} artifacts found in {}", dir.display())]
+    ArtifactsMissing { project: &'static str, dir: PathBuf },
+    #[error("{tag} timed out after {secs}s and was killed: {command}")]
+    Timeout { tag: String, secs: u64, command: String },
+    #[error("Install is incomplete, missing {}", .0.iter().map(|path| path.display().to_string()).collect::<Vec<_>>().join(", "))]
+    InstallIncomplete(Vec<PathBuf>),
+    #[error("SHA-256 mismatch for {}, expected {expected}, got {actual}", path.display())]
+    ChecksumMismatch { path: PathBuf, expected: String, actual: String },
+    #[error("{failed} of {total} install phases failed")]
+    PhasesFailed { failed: usize, total: usize },
+    #[error("Only {available} GiB free for {}, --min-free-space asks for {required} GiB", path.display())]
+    DiskSpace { path: PathBuf, available: String, required: u64 },
+    #[error("{failed} of {total} platforms failed to install")]
+    PlatformsFailed { failed: usize, total: usize, exit_code: i32 },
+    #[error("Refusing to delete {} without a terminal to confirm on, pass --yes", .0.display())]
+    DeleteUnconfirmed(PathBuf),
+    #[error("Not deleting {}, aborting", .0.display())]
+    DeleteDeclined(PathBuf),
+    #[error("The {0} install panicked")]
+    InstallPanicked(&'static str),
+    #[error("Invalid {0} path")]
+    InvalidPath(&'static str),
+    #[error("Failed to load config file {}: {reason}", path.display())]
+    ConfigFile { path: PathBuf, reason: String },
+    #[error("Invalid options: {0}")]
+    Usage(String),
+}
+
+impl InstallError {
+    /// The name of the variant, e.g. `CloneFailed`, for the `--format json` error
+    pub(crate) fn kind(&self) -> String {
+        let debug = format!("{:?}", self);
+        debug.split(|c: char| !c.is_ascii_alphanumeric()).next().unwrap_or_default().to_string()
+    }
+
+    /// The process exit code of the failure, documented in `--help`
+    pub(crate) fn exit_code(&self) -> i32 {
+        match self {
+            InstallError::ToolMissing(_)
+            | InstallError::ToolNotFound { .. }
+            | InstallError::ToolchainsMissing { .. }
+            | InstallError::ToolchainInstall(_)
+            | InstallError::CrossCompilerMissing { .. }
+            | InstallError::ToolsMissing(_) => 6,
+            InstallError::CloneFailed { .. } | InstallError::FetchFailed { .. } => 3,
+            InstallError::CmakeConfigure { .. }
+            | InstallError::NinjaBuild { .. }
+            | InstallError::BuildFailed(_)
+            | InstallError::CargoInstall { .. }
+            | InstallError::ArtifactsMissing { .. }
+            | InstallError::InstallIncomplete(_)
+            | InstallError::ChecksumMismatch { .. }
+            | InstallError::InstallPanicked(_) => 4,
+            InstallError::Usage(_) => 2,
+            InstallError::Timeout { .. } => 5,
+            InstallError::PlatformsFailed { exit_code, .. } => *exit_code,
+            _ => 1,
+        }
+    }
+}
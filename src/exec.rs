@@ -0,0 +1,465 @@
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{BufRead, BufReader, IsTerminal, Read, Write};
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use indicatif::{ProgressBar, ProgressStyle};
+
+use crate::error::InstallError;
+use crate::time;
+
+/// How child processes are run, set once from the global options
+#[derive(Debug, Default)]
+pub(crate) struct Settings {
+    /// Relay child output line by line, prefixed with the command tag
+    pub verbose: bool,
+    /// Print commands and file operations as shell instead of running them
+    pub dry_run: bool,
+    /// Drop child stdout, keeping stderr for the diagnostics, `verbose` wins over it
+    pub quiet: bool,
+    /// Kill a child still running after this long
+    pub timeout: Option<Duration>,
+    /// Print the environment changes of each command before running it
+    pub print_env: bool,
+    /// Delete existing dirs without asking
+    pub yes: bool,
+    /// Keep stdout for the `--format json` result, everything else goes to stderr
+    pub json: bool,
+    /// Color the status words of the output with ANSI escapes
+    pub color: bool,
+}
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+pub(crate) fn init(settings: Settings) {
+    if SETTINGS.set(settings).is_err() {
+        // Already initialized, the first settings win
+    }
+}
+
+fn settings() -> &'static Settings {
+    SETTINGS.get_or_init(Settings::default)
+}
+
+pub(crate) fn dry_run() -> bool {
+    settings().dry_run
+}
+
+pub(crate) fn json() -> bool {
+    settings().json
+}
+
+pub(crate) fn color() -> bool {
+    settings().color
+}
+
+/// The ANSI escapes `paint` colors with
+pub(crate) const RED: &str = "1;31";
+pub(crate) const GREEN: &str = "32";
+pub(crate) const YELLOW: &str = "33";
+
+/// The text in the color, as is when `--color` or `NO_COLOR` turn color off
+pub(crate) fn paint(text: &str, color: &str) -> String {
+    if settings().color {
+        format!("\x1b[{}m{}\x1b[0m", color, text)
+    } else {
+        text.to_string()
+    }
+}
+
+/// Print a warning on stderr
+pub(crate) fn warn(message: impl std::fmt::Display) {
+    eprintln!("{} {}", paint("warning:", YELLOW), message);
+}
+
+/// Print a line of human output, on stderr with `--format json` so stdout only carries the JSON
+pub(crate) fn report(line: impl std::fmt::Display) {
+    if json() {
+        eprintln!("{}", line);
+    } else {
+        println!("{}", line);
+    }
+}
+
+/// Ask on the terminal before deleting the existing dir at path, `--yes` skips the question.
+/// Without a terminal to ask on, deleting needs `--yes`
+pub(crate) fn confirm_remove(path: &Path) -> anyhow::Result<()> {
+    if settings().yes || settings().dry_run || !path.exists() {
+        return Ok(());
+    }
+    if !std::io::stdin().is_terminal() {
+        return Err(InstallError::DeleteUnconfirmed(path.to_path_buf()).into());
+    }
+
+    eprint!("Delete {}? [y/N] ", path.display());
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    if matches!(answer.trim(), "y" | "Y" | "yes") {
+        Ok(())
+    } else {
+        Err(InstallError::DeleteDeclined(path.to_path_buf()).into())
+    }
+}
+
+/// The `--log-file` every child output line is also written to
+static LOG: Mutex<Option<File>> = Mutex::new(None);
+
+/// Tee the output of every command run from now on into a new log file at path
+pub(crate) fn open_log(path: &Path) -> anyhow::Result<()> {
+    let file = File::create(path).map_err(|err| anyhow::anyhow!("Failed to create log file {}: {}", path.display(), err))?;
+    if let Ok(mut log) = LOG.lock() {
+        *log = Some(file);
+    }
+    Ok(())
+}
+
+fn logging() -> bool {
+    LOG.lock().is_ok_and(|log| log.is_some())
+}
+
+/// Append a line to the log file, if there is one
+fn log(line: &str) {
+    if let Ok(mut log) = LOG.lock() {
+        if let Some(file) = log.as_mut() {
+            if writeln!(file, "{}", line).is_err() {
+                // Losing the log must not fail the install
+            }
+        }
+    }
+}
+
+/// The spinner of the running phase, child output is printed around it
+static SPINNER: Mutex<Option<ProgressBar>> = Mutex::new(None);
+
+fn spinner() -> Option<ProgressBar> {
+    SPINNER.lock().ok()?.clone()
+}
+
+/// Run `f` under a spinner showing `message` and the elapsed time, cleared when `f` returns.
+/// There is no spinner when stdout is not a terminal, with `--verbose` or with `--dry-run`
+pub(crate) fn phase<T>(message: &str, f: impl FnOnce() -> T) -> T {
+    let settings = settings();
+    if settings.verbose || settings.dry_run || !std::io::stdout().is_terminal() || spinner().is_some() {
+        return f();
+    }
+
+    let style = ProgressStyle::with_template("{spinner} {msg} [{elapsed}]").unwrap_or_else(|_| ProgressStyle::default_spinner());
+    let bar = ProgressBar::new_spinner().with_style(style).with_message(format!("{}...", message));
+    bar.enable_steady_tick(Duration::from_millis(100));
+    if let Ok(mut spinner) = SPINNER.lock() {
+        *spinner = Some(bar.clone());
+    }
+    let result = f();
+    if let Ok(mut spinner) = SPINNER.lock() {
+        *spinner = None;
+    }
+    bar.finish_and_clear();
+    result
+}
+
+/// Run the command to completion and return its exit status.
+/// `tag` names the command in verbose output, e.g. `cmake` or `ninja`
+pub(crate) fn status(command: &mut Command, tag: &str) -> anyhow::Result<ExitStatus> {
+    if dry_run() {
+        report(display(command));
+        return Ok(ExitStatus::from_raw(0));
+    }
+
+    let settings = settings();
+    let quiet = settings.quiet && !settings.verbose;
+    let logging = logging();
+    if settings.print_env {
+        print_env(command);
+    }
+
+    // Under a spinner the output is relayed as is, so the spinner can be redrawn below it.
+    // With a log file it is relayed to be teed into the log
+    if !settings.verbose && !logging && spinner().is_none() {
+        if quiet {
+            command.stdout(Stdio::null());
+        } else if settings.json {
+            // Our stdout only holds the JSON report
+            command.stdout(std::io::stderr());
+        }
+        let mut child = spawn(command)?;
+        return wait(&mut child, command, tag);
+    }
+    let relay_tag = settings.verbose.then_some(tag);
+
+    if logging {
+        log(&format!("==> {} {}", time::format_utc(time::now()), display(command)));
+    }
+    let mut child = spawn(command.stdout(Stdio::piped()).stderr(Stdio::piped()))?;
+    let stdout = child.stdout.take().map(|stdout| relay(stdout, relay_tag, Stream::Stdout { shown: !quiet }));
+    let stderr = child.stderr.take().map(|stderr| relay(stderr, relay_tag, Stream::Stderr));
+    let status = wait(&mut child, command, tag);
+    for relay in [stdout, stderr].into_iter().flatten() {
+        if relay.join().is_err() {
+            return Err(anyhow::anyhow!("Failed to relay the output of {}", command.get_program().to_string_lossy()));
+        }
+    }
+    let status = status?;
+    if logging {
+        log(&format!("<== {}", status));
+    }
+    Ok(status)
+}
+
+/// Spawn the command, telling how to install the build tools missing from PATH instead of
+/// failing with a bare IO error
+fn spawn(command: &mut Command) -> anyhow::Result<Child> {
+    let err = match command.spawn() {
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => err,
+        result => return Ok(result?),
+    };
+    let program = command.get_program().to_string_lossy().into_owned();
+    let hint = match program.as_str() {
+        "rustup" => "install it from https://rustup.rs and the pinned nightly toolchains.",
+        "cargo" => "install it with rustup from https://rustup.rs.",
+        "git" => "install it with the package manager, e.g. `apt install git`.",
+        "cmake" => "install it with the package manager, e.g. `apt install cmake`.",
+        "ninja" => "install it with the package manager, e.g. `apt install ninja-build`.",
+        _ => return Err(anyhow::Error::new(err).context(format!("Failed to run {}", program))),
+    };
+    Err(InstallError::ToolNotFound { tool: program, hint }.into())
+}
+
+/// Print the variables the command sets and unsets on top of our own environment
+fn print_env(command: &Command) {
+    let mut set = Vec::new();
+    let mut unset = Vec::new();
+    for (key, value) in command.get_envs() {
+        match value {
+            Some(value) => set.push(format!("{}={}", quote(key), quote(value))),
+            None => unset.push(quote(key)),
+        }
+    }
+    if set.is_empty() && unset.is_empty() {
+        return;
+    }
+    let program = command.get_program().to_string_lossy();
+    let print = || {
+        for var in &set {
+            eprintln!("[env {}] {}", program, var);
+        }
+        for var in &unset {
+            eprintln!("[env {}] unset {}", program, var);
+        }
+    };
+    match spinner() {
+        Some(spinner) => spinner.suspend(print),
+        None => print(),
+    }
+}
+
+/// Wait for the child, killing it once `--timeout` passes
+fn wait(child: &mut Child, command: &Command, tag: &str) -> anyhow::Result<ExitStatus> {
+    let Some(timeout) = settings().timeout else {
+        return Ok(child.wait()?);
+    };
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        if Instant::now() >= deadline {
+            child.kill()?;
+            child.wait()?;
+            log(&format!("<== killed after {}s", timeout.as_secs()));
+            return Err(InstallError::Timeout { tag: tag.to_string(), secs: timeout.as_secs(), command: display(command) }.into());
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Which output of a child is relayed, stdout is hidden by `--quiet` but still logged
+#[derive(Clone, Copy)]
+enum Stream {
+    Stdout { shown: bool },
+    Stderr,
+}
+
+/// Relay each line of `output` to our own stdout or stderr, with a `[tag]` prefix if given,
+/// and into the log file
+fn relay(output: impl Read + Send + 'static, tag: Option<&str>, stream: Stream) -> std::thread::JoinHandle<()> {
+    let prefix = tag.map(|tag| format!("[{}] ", tag)).unwrap_or_default();
+    std::thread::spawn(move || {
+        let mut reader = BufReader::new(output);
+        let mut line = Vec::new();
+        while reader.read_until(b'\n', &mut line).is_ok_and(|len| len > 0) {
+            let text = String::from_utf8_lossy(&line);
+            let text = text.trim_end_matches(['\r', '\n']);
+            log(text);
+            let print = || match stream {
+                Stream::Stdout { shown: true } => report(format_args!("{}{}", prefix, text)),
+                Stream::Stdout { shown: false } => {}
+                Stream::Stderr => eprintln!("{}{}", prefix, text),
+            };
+            match spinner() {
+                Some(spinner) => spinner.suspend(print),
+                None => print(),
+            }
+            line.clear();
+        }
+    })
+}
+
+/// The command as a line of shell, including its working dir and environment changes
+pub(crate) fn display(command: &Command) -> String {
+    let mut line = String::new();
+    let envs: Vec<_> = command.get_envs().collect();
+    if !envs.is_empty() {
+        line.push_str("env");
+        // env takes the first word after an assignment as the command, so the unsets go first
+        for (key, _) in envs.iter().filter(|(_, value)| value.is_none()) {
+            line.push_str(&format!(" -u {}", quote(key)));
+        }
+        for (key, value) in envs.iter() {
+            if let Some(value) = value {
+                line.push_str(&format!(" {}={}", quote(key), quote(value)));
+            }
+        }
+        line.push(' ');
+    }
+    line.push_str(&quote(command.get_program()));
+    for arg in command.get_args() {
+        line.push(' ');
+        line.push_str(&quote(arg));
+    }
+
+    match command.get_current_dir() {
+        Some(dir) => format!("(cd {} && {})", quote(dir.as_os_str()), line),
+        None => line,
+    }
+}
+
+/// Quote an argument for the shell when it contains anything special
+fn quote(arg: &OsStr) -> String {
+    let arg = arg.to_string_lossy();
+    let plain = !arg.is_empty() && arg.chars().all(|c| c.is_ascii_alphanumeric() || "_-./=:@%+,".contains(c));
+    if plain {
+        arg.into_owned()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+pub(crate) fn remove_dir_all(path: impl AsRef<Path>) -> std::io::Result<()> {
+    let path = path.as_ref();
+    if dry_run() {
+        if path.exists() {
+            report(format_args!("rm -rf {}", quote(path.as_os_str())));
+        }
+        return Ok(());
+    }
+    std::fs::remove_dir_all(path)
+}
+
+pub(crate) fn remove_file(path: impl AsRef<Path>) -> std::io::Result<()> {
+    let path = path.as_ref();
+    if dry_run() {
+        if path.exists() {
+            report(format_args!("rm -f {}", quote(path.as_os_str())));
+        }
+        return Ok(());
+    }
+    std::fs::remove_file(path)
+}
+
+pub(crate) fn create_dir_all(path: impl AsRef<Path>) -> std::io::Result<()> {
+    let path = path.as_ref();
+    if dry_run() {
+        report(format_args!("mkdir -p {}", quote(path.as_os_str())));
+        return Ok(());
+    }
+    std::fs::create_dir_all(path)
+}
+
+pub(crate) fn copy(from: impl AsRef<Path>, to: impl AsRef<Path>) -> std::io::Result<()> {
+    let (from, to) = (from.as_ref(), to.as_ref());
+    if dry_run() {
+        report(format_args!("cp {} {}", quote(from.as_os_str()), quote(to.as_os_str())));
+        return Ok(());
+    }
+    std::fs::copy(from, to).map(|_| ())
+}
+
+pub(crate) fn write(path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> std::io::Result<()> {
+    let path = path.as_ref();
+    if dry_run() {
+        report(format_args!("# write {}", quote(path.as_os_str())));
+        return Ok(());
+    }
+    std::fs::write(path, contents)
+}
+
+/// Recursively copy the contents of `src` into `dst`, merging with what is already there
+pub(crate) fn copy_dir_all(src: &Path, dst: &Path) -> std::io::Result<()> {
+    if dry_run() {
+        report(format_args!("mkdir -p {dst} && cp -r {src}/. {dst}", src = quote(src.as_os_str()), dst = quote(dst.as_os_str())));
+        return Ok(());
+    }
+
+    std::fs::create_dir_all(dst)?;
+    for entry in std::fs::read_dir(src)? {
+        let entry = entry?;
+        let target = dst.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir_all(&entry.path(), &target)?;
+        } else {
+            std::fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Canonicalize the path, in dry run mode a missing path is returned as is
+/// because the clone that would create it was only printed
+pub(crate) fn canonicalize(path: impl AsRef<Path>) -> std::io::Result<PathBuf> {
+    let path = path.as_ref();
+    if dry_run() && !path.exists() {
+        return Ok(path.to_path_buf());
+    }
+    std::fs::canonicalize(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quote_leaves_plain_args() {
+        assert_eq!(quote(OsStr::new("-DKernelPlatform=spike")), "-DKernelPlatform=spike");
+        assert_eq!(quote(OsStr::new("/tmp/rel4_kernel")), "/tmp/rel4_kernel");
+    }
+
+    #[test]
+    fn quote_quotes_special_args() {
+        assert_eq!(quote(OsStr::new("")), "''");
+        assert_eq!(quote(OsStr::new("a b")), "'a b'");
+        assert_eq!(quote(OsStr::new("it's")), "'it'\\''s'");
+        assert_eq!(quote(OsStr::new("$HOME")), "'$HOME'");
+    }
+
+    #[test]
+    fn display_puts_unsets_before_assignments() {
+        let mut command = Command::new("rustup");
+        command.args(["run", "nightly", "cargo", "build"])
+            .env_remove("CARGO")
+            .env("SEL4_PREFIX", "/opt/sel4 prefix")
+            .current_dir("/tmp/rel4");
+        assert_eq!(display(&command), "(cd /tmp/rel4 && env -u CARGO SEL4_PREFIX='/opt/sel4 prefix' rustup run nightly cargo build)");
+    }
+
+    #[test]
+    fn display_without_env_and_dir() {
+        let mut command = Command::new("ninja");
+        command.args(["-C", "build", "install"]);
+        assert_eq!(display(&command), "ninja -C build install");
+    }
+}
@@ -1,7 +1,53 @@
 use anyhow::Ok;
-use clap::Parser;
+use clap::{ArgMatches, Parser};
 use std::{process::Command, vec};
 
+use crate::config::{check_options, KernelConfig};
+use crate::error::InstallError;
+use crate::exec;
+use crate::lock;
+use crate::manifest::Manifest;
+use crate::platform::{platform_spec, Platform, RiscvExt};
+use crate::time;
+
+/// The pinned toolchain for building the reL4 kernel
+pub(crate) const KERNEL_TOOLCHAIN: &str = "nightly-2024-02-01";
+/// The pinned toolchain for building the kernel loader and the rust-sel4 based userspace
+pub(crate) const LOADER_TOOLCHAIN: &str = "nightly-2024-08-01";
+
+/// The repos the reL4 kernel is built from by default
+const REL4_REPO: &str = "https://github.com/reL4team2/rel4-integral.git";
+const SEL4_C_IMPL_REPO: &str = "https://github.com/reL4team2/seL4_c_impl.git";
+
+/// The pinned rust-sel4 repo and rev the kernel loader is installed from
+const LOADER_REPO: &str = "https://github.com/reL4team2/rust-sel4.git";
+pub(crate) const LOADER_REV: &str = "642b58d807c5e5fc22f0c15d1467d6bec328faa9";
+
+/// The working dir under `--tmp-dir` the rel4-integral repo is cloned into
+pub(crate) const REL4_KERNEL_DIR: &str = "rel4_kernel";
+/// The working dir seL4_c_impl, or the upstream seL4 for baseline builds, is cloned into
+pub(crate) const SEL4_KERNEL_DIR: &str = "seL4_kernel";
+/// The working dir reL4-linux-kit is cloned into
+pub(crate) const LINUX_KIT_DIR: &str = "rel4_linux_kit";
+/// The working dir the upstream seL4 for libsel4 only installs is cloned into
+pub(crate) const LIBSEL4_DIR: &str = "seL4_libsel4";
+/// The `--tmp-dir` the working dirs are under by default
+pub(crate) const DEFAULT_TMP_DIR: &str = "/tmp";
+
+/// The prefix of the project dev container, the default before the per-user one
+const CONTAINER_PREFIX: &str = "/workspace/.seL4";
+
+/// The `--prefix` the kernel is installed into by default, `$XDG_DATA_HOME/rel4` falling back
+/// to `~/.local/share/rel4`. The container prefix when there is no home to install into
+pub(crate) fn default_prefix() -> String {
+    dirs::data_dir().map_or_else(|| CONTAINER_PREFIX.to_string(), |dir| dir.join("rel4").display().to_string())
+}
+
+/// The path of the working dir `name` under `tmp_dir`
+pub(crate) fn work_dir(tmp_dir: &str, name: &str) -> String {
+    std::path::Path::new(tmp_dir).join(name).to_string_lossy().into_owned()
+}
+
 #[derive(Debug, Parser)]
 pub(crate) struct InstallOptions {
     #[clap(subcommand)]
@@ -12,24 +58,117 @@ pub(crate) struct InstallOptions {
 enum InstallCommand {
     /// Install reL4 kernel, libseL4, kernel loader, which needs by the userspace development
     #[command(about = "Install reL4 kernel, libseL4, kernel loader")]
-    Kernel(KernelOptions),
+    Kernel(Box<KernelOptions>),
+    /// Install reL4-linux-kit, the Linux compatible userspace running on reL4
+    #[command(about = "Install reL4-linux-kit")]
+    LinuxKit(LinuxKitOptions),
+    /// Install libseL4 headers and static library only, without building the kernel
+    #[command(name = "libsel4", about = "Install libseL4 headers and static library")]
+    LibSel4(LibSel4Options),
 }
 
-pub(crate) fn install(opts: InstallOptions) -> anyhow::Result<()> {
+/// `prefix` and `tmp_dir` are the global `--prefix` and `--tmp-dir`. `matches` are the top
+/// level matches, which tell the options given on the command line from those left to their defaults
+pub(crate) fn install(opts: InstallOptions, prefix: &str, tmp_dir: &str, matches: &ArgMatches) -> anyhow::Result<()> {
+    let matches = matches.subcommand_matches("install");
     match opts.command {
-        InstallCommand::Kernel(kernel_opts) => {
-            install_kernel(&kernel_opts, &kernel_opts.sel4_prefix)?;
-            install_kernel_loader(&kernel_opts, &kernel_opts.sel4_prefix)?;
+        InstallCommand::Kernel(mut kernel_opts) => {
+            kernel_opts.sel4_prefix = prefix.to_string();
+            kernel_opts.tmp_dir = tmp_dir.to_string();
+            let kernel_matches = matches.and_then(|matches| matches.subcommand_matches("kernel"));
+            if let Some(discovered) = KernelConfig::discover() {
+                eprintln!("Using config file {}", discovered.display());
+                KernelConfig::load(&discovered)?.merge(&mut kernel_opts, kernel_matches);
+            }
+            if let Some(config_file) = kernel_opts.config_file.clone() {
+                KernelConfig::load(&config_file)?.merge(&mut kernel_opts, kernel_matches);
+            }
+            check_options(&kernel_opts)?;
+            if kernel_opts.dump_config {
+                if exec::json() {
+                    println!("{}", serde_json::to_string_pretty(&kernel_opts)?);
+                } else {
+                    print!("{}", toml::to_string(&kernel_opts)?);
+                }
+                return Ok(());
+            }
+            if kernel_opts.kernel_name.is_empty() || kernel_opts.kernel_name.contains('/') {
+                return Err(InstallError::InvalidPath("--kernel-name").into());
+            }
+            let mut platforms = Vec::new();
+            for platform in &kernel_opts.platforms {
+                if !platforms.contains(platform) {
+                    platforms.push(*platform);
+                }
+            }
+            if let [platform] = platforms[..] {
+                kernel_opts.platform = platform;
+                if kernel_opts.dump_cmake_command {
+                    return dump_cmake_command(&kernel_opts, &kernel_opts.sel4_prefix);
+                }
+                let report = install_platform(&kernel_opts, &kernel_opts.sel4_prefix, &mut None)?;
+                if exec::json() {
+                    println!("{}", serde_json::to_string(&report)?);
+                }
+                return Ok(());
+            }
+            // Each platform goes into its own subprefix, the sources are cloned once for all of them
+            let mut sources = None;
+            let mut results = Vec::new();
+            for platform in platforms {
+                kernel_opts.platform = platform;
+                let prefix = format!("{}/{}", kernel_opts.sel4_prefix.trim_end_matches('/'), platform);
+                if kernel_opts.dump_cmake_command {
+                    dump_cmake_command(&kernel_opts, &prefix)?;
+                    continue;
+                }
+                exec::report(format_args!("==> Installing {} into {}", platform, prefix));
+                results.push((platform, install_platform(&kernel_opts, &prefix, &mut sources)));
+            }
+            for (platform, result) in &results {
+                match result {
+                    Err(err) => exec::report(format_args!("{:<20}{}: {:#}", platform.name(), exec::paint("failed", exec::RED), err)),
+                    _ => exec::report(format_args!("{:<20}{}", platform.name(), exec::paint("ok", exec::GREEN))),
+                }
+            }
+            if exec::json() && !kernel_opts.dump_cmake_command {
+                println!("{}", platforms_report(&results)?);
+            }
+            let codes: Vec<i32> = results.iter()
+                .filter_map(|(_, result)| result.as_ref().err())
+                .map(|err| err.downcast_ref::<InstallError>().map_or(1, InstallError::exit_code))
+                .collect();
+            if let Some(&first) = codes.first() {
+                // Failures of one kind, e.g. all clones, keep its exit code so CI can tell a retry helps
+                let exit_code = if codes.iter().all(|&code| code == first) { first } else { 1 };
+                return Err(InstallError::PlatformsFailed { failed: codes.len(), total: results.len(), exit_code }.into());
+            }
+        }
+        InstallCommand::LinuxKit(linux_kit_opts) => {
+            let _lock = lock::acquire(prefix)?;
+            install_linux_kit(&linux_kit_opts, prefix, tmp_dir)?;
+        }
+        InstallCommand::LibSel4(libsel4_opts) => {
+            let _lock = lock::acquire(prefix)?;
+            install_libsel4(&libsel4_opts, prefix, tmp_dir)?;
         }
     }
     Ok(())
 }
 
-#[derive(Debug, Parser)]
-struct KernelOptions {
-    /// The target platform to install
-    #[clap(default_value = "qemu-arm-virt", short, long)]
-    pub platform: String,
+/// Serialized by `--dump-config` in the `--config-file` format, skipping the options a config
+/// file can not set
+#[derive(Debug, Parser, serde::Serialize)]
+pub(crate) struct KernelOptions {
+    /// The target platforms to install, e.g. `qemu-arm-virt,spike`. Several platforms are installed
+    /// into `<prefix>/<platform>` each. The flag wins over `REL4_PLATFORM`, which wins over the default
+    #[clap(default_value = "qemu-arm-virt", short, long = "platform", value_delimiter = ',', env = "REL4_PLATFORM")]
+    #[serde(rename = "platform")]
+    pub platforms: Vec<Platform>,
+    /// The platform being installed, one of `platforms`
+    #[clap(skip = Platform::QemuArmVirt)]
+    #[serde(skip)]
+    pub platform: Platform,
     /// Enable kernel mcs mode
     #[clap(short, long)]
     pub mcs: bool,
@@ -44,196 +183,1106 @@ struct KernelOptions {
     /// If you want to use binary mode, please set this option.
     #[clap(long, short = 'B')]
     pub bin: bool,
-    /// seL4 prefix path
-    #[clap(short = 'P', long, default_value = "/workspace/.seL4")]
+    /// File name the `--bin` kernel is installed as under `<prefix>/bin`
+    #[clap(long, value_name = "NAME", default_value = "kernel.elf")]
+    pub kernel_name: String,
+    /// seL4 prefix path, from the global `--prefix` or the config file
+    #[clap(skip)]
     pub sel4_prefix: String,
-    /// Local reL4 kernel path
-    #[clap(short = 'L', long)]
+    /// Where the working clones are made, from the global `--tmp-dir` or the config file
+    #[clap(skip)]
+    pub tmp_dir: String,
+    /// Local reL4 kernel path, seL4_c_impl is expected at `<local>/../kernel` unless `--local-sel4` is given.
+    /// Nothing is cloned, so the clone options are rejected with it
+    #[clap(short = 'L', long, conflicts_with_all = [
+        "branch", "rel4_commit", "sel4_c_impl_branch", "sel4_c_impl_commit", "depth", "full_clone", "force_clone", "rel4_repo", "sel4_repo", "patch",
+    ])]
     pub local: Option<String>,
+    /// Local seL4_c_impl path
+    #[clap(long)]
+    pub local_sel4: Option<String>,
     /// rel4 kernel branch
     #[clap(long, default_value = "master")]
     pub branch: String,
-    /// force install
+    /// rel4 kernel commit to build, checked out after cloning, instead of `--branch`
+    #[clap(long, conflicts_with = "branch")]
+    pub rel4_commit: Option<String>,
+    /// seL4_c_impl branch, its default branch when unset
+    #[clap(long)]
+    pub sel4_c_impl_branch: Option<String>,
+    /// seL4_c_impl commit to build, checked out after cloning, instead of `--sel4-c-impl-branch`
+    #[clap(long, conflicts_with = "sel4_c_impl_branch")]
+    pub sel4_c_impl_commit: Option<String>,
+    /// force install, both `--force-clone` and `--force-loader`
     #[clap(long)]
     pub force: bool,
-    /// seL4 baseline version
+    /// How many times a failed git clone is attempted, with a doubling delay from 1s between attempts
+    #[clap(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+    pub clone_retries: u32,
+    /// Wipe and re-clone rel4-integral and seL4_c_impl even if they are already cloned
     #[clap(long)]
+    pub force_clone: bool,
+    /// Reinstall the kernel loader even if cargo considers it up to date
+    #[clap(long)]
+    pub force_loader: bool,
+    /// seL4 baseline version, a commit, a branch or tag, or `latest` for the tip of master.
+    /// The upstream seL4 is built instead of reL4, so the reL4 options are rejected with it
+    #[clap(long, conflicts_with_all = [
+        "bin", "local", "local_sel4", "branch", "rel4_commit", "sel4_c_impl_branch", "sel4_c_impl_commit",
+        "rel4_repo", "sel4_repo", "home_fixup", "patch", "xtask_arg", "profile", "sel4_config",
+    ])]
     pub sel4_baseline: Option<String>,
+    /// Number of parallel ninja jobs, ninja's default when unset
+    #[clap(short, long)]
+    pub jobs: Option<usize>,
+    /// Rust toolchain used to build the reL4 kernel
+    #[clap(long, default_value = KERNEL_TOOLCHAIN)]
+    pub kernel_toolchain: String,
+    /// Rust toolchain used to build the kernel loader
+    #[clap(long, default_value = LOADER_TOOLCHAIN)]
+    pub loader_toolchain: String,
+    /// Cross compiler prefix, e.g. `aarch64-linux-gnu-`, the platform default when unset
+    #[clap(long)]
+    pub cross_compiler_prefix: Option<String>,
+    /// ARM CPU model the kernel is built for, ignored on non ARM platforms [default: the platform's, e.g. cortex-a57]
+    #[clap(long)]
+    pub arm_cpu: Option<String>,
+    /// RISC-V ISA extensions of the kernel and loader target triple, e.g. `gc` for hardware
+    /// float on spike [default: imac]
+    #[clap(long, value_enum)]
+    pub riscv_ext: Option<RiscvExt>,
+    /// Enable ARM hypervisor support, for virtualization on ARM platforms
+    #[clap(long)]
+    pub hypervisor: bool,
+    /// Number of cores the kernel supports, SMP is enabled when more than one
+    #[clap(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    pub smp: u32,
+    /// Load options from a TOML file, using the option names as keys, e.g. `mcs = true`.
+    /// Options given on the command line or through `REL4_PREFIX`/`REL4_PLATFORM` override the file.
+    /// A `rel4-cli.toml` in the current dir or one of its parents is loaded first, under this file
+    #[clap(long)]
+    #[serde(skip)]
+    pub config_file: Option<std::path::PathBuf>,
+    /// Clone depth of rel4-integral and seL4_c_impl
+    #[clap(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    pub depth: u32,
+    /// Clone the full history of rel4-integral and seL4_c_impl, ignoring `--depth`
+    #[clap(long, conflicts_with = "depth")]
+    pub full_clone: bool,
+    /// Never touch the network, reuse the existing clones under `--tmp-dir` or `--local`
+    /// and the cached loader sources
+    #[clap(long)]
+    pub offline: bool,
+    /// Pin the home crate of rel4-integral from 0.5.11 back to 0.5.5 after cloning
+    #[clap(long, value_enum, default_value_t = HomeFixup::Auto)]
+    pub home_fixup: HomeFixup,
+    /// Remove the CMake build dir before configuring, this is done anyway when the
+    /// build dir was configured for another platform
+    #[clap(long)]
+    pub clean_build: bool,
+    /// Clone and install from `<base>/...` instead of `https://github.com/...`, e.g. for a proxy of GitHub
+    #[clap(long, value_name = "BASE")]
+    pub git_mirror: Option<String>,
+    /// rel4-integral repo to clone, e.g. a fork, `--branch` applies to it
+    #[clap(long, default_value = REL4_REPO)]
+    pub rel4_repo: String,
+    /// seL4_c_impl repo to clone, e.g. a fork
+    #[clap(long, default_value = SEL4_C_IMPL_REPO)]
+    pub sel4_repo: String,
+    /// rust-sel4 repo the kernel loader is installed from, e.g. a fork
+    #[clap(long, default_value = LOADER_REPO)]
+    pub loader_repo: String,
+    /// rust-sel4 rev the kernel loader is installed from
+    #[clap(long, default_value = LOADER_REV)]
+    pub loader_rev: String,
+    /// Skip checking for the cross compiler and the Rust toolchains before cloning
+    #[clap(long)]
+    pub skip_tool_check: bool,
+    /// Install the missing Rust toolchains, targets and rust-src with rustup instead of failing
+    #[clap(long)]
+    pub auto_toolchain: bool,
+    /// Abort before building unless the tmp dir and prefix filesystems have this many GiB free,
+    /// otherwise a low estimate only warns
+    #[clap(long, value_name = "GIB")]
+    pub min_free_space: Option<u64>,
+    /// Extra CMake arg for the kernel build, e.g. `-DKernelPrinting=ON`, can be repeated.
+    /// These come after the built-in flags, so they override them
+    #[clap(long, value_name = "FLAG", allow_hyphen_values = true)]
+    pub cmake_arg: Vec<String>,
+    /// Keep the build dir of a failed cmake configure for debugging, it is removed by default
+    #[clap(long)]
+    pub keep_failed_build: bool,
+    /// Kernel settings file the seL4_c_impl build is configured with instead of the platform's
+    /// `kernel-settings-*.cmake`, relative to the seL4_c_impl dir unless absolute
+    #[clap(long, value_name = "FILE")]
+    pub sel4_config: Option<std::path::PathBuf>,
+    /// Extra arg for the reL4 `cargo xtask build`, e.g. `--xtask-arg=--foo`, can be repeated
+    #[clap(long, value_name = "ARG", allow_hyphen_values = true)]
+    pub xtask_arg: Vec<String>,
+    /// Build profile of the reL4 kernel, debug keeps the debug symbols
+    #[clap(long, value_enum, default_value_t = Profile::Release)]
+    pub profile: Profile,
+    /// Patch to `git apply` in the rel4-integral clone before building, repeatable and applied
+    /// in order. Not taken with `--local`, whose checkout is never modified
+    #[clap(long, value_name = "FILE")]
+    pub patch: Vec<std::path::PathBuf>,
+    /// Kernel benchmark instrumentation, exporting the ARM PMU to userspace when enabled
+    #[clap(long, value_enum, default_value_t = Benchmark::None)]
+    pub benchmark: Benchmark,
+    /// Build a debug kernel with printing and asserts, whatever the kernel settings choose
+    #[clap(long, conflicts_with = "kernel_release")]
+    pub kernel_debug: bool,
+    /// Build a kernel without debug printing and asserts, whatever the kernel settings choose
+    #[clap(long)]
+    pub kernel_release: bool,
+    /// Fail the install unless the installed kernel has this SHA-256, in hex
+    #[clap(long, value_name = "HEX")]
+    pub expect_sha256: Option<String>,
+    /// Skip installing the kernel loader
+    #[clap(long, conflicts_with = "loader_only")]
+    pub no_loader: bool,
+    /// Only install the host tool sel4-kernel-loader-add-payload of the loader, not the target
+    /// built sel4-kernel-loader. Add `--loader-only` to skip the kernel too
+    #[clap(long, conflicts_with = "no_loader")]
+    pub payload_tool_only: bool,
+    /// Only install the kernel loader, skip building the kernel, e.g. after bumping `--loader-rev`.
+    /// The loader is built against the kernel already installed under prefix
+    #[clap(long, visible_alias = "skip-kernel")]
+    pub loader_only: bool,
+    /// `CARGO_TARGET_DIR` of the kernel loader installs, reusing it makes later installs incremental
+    #[clap(long)]
+    pub cargo_target_dir: Option<std::path::PathBuf>,
+    /// `CARGO_HOME` of the kernel loader installs, e.g. a cache shared between CI runs
+    #[clap(long)]
+    pub cargo_home: Option<std::path::PathBuf>,
+    /// Embed this application ELF into the kernel loader, producing a bootable image
+    #[clap(long, value_name = "APP_ELF")]
+    pub with_payload: Option<std::path::PathBuf>,
+    /// Where the bootable image of `--with-payload` is written [default: <prefix>/bin/image.elf]
+    #[clap(long, requires = "with_payload")]
+    pub image: Option<std::path::PathBuf>,
+    /// Install the two kernel loader crates one after the other instead of at the same time
+    #[clap(long)]
+    pub sequential: bool,
+    /// Run the kernel, loader and image phases even if one fails, reporting which failed at the end
+    #[clap(long)]
+    pub continue_on_error: bool,
+    /// Print the cmake configure command of the kernel build and exit, without cloning or building
+    #[clap(long)]
+    #[serde(skip)]
+    pub dump_cmake_command: bool,
+    /// Print the options after merging the config file and environment, as a config file
+    /// (JSON with `--format json`) and exit, without cloning or building
+    #[clap(long)]
+    #[serde(skip)]
+    pub dump_config: bool,
 }
 
-/// Install kernel, seL4 or reL4
-fn install_kernel(opts: &KernelOptions, prefix: &str) -> anyhow::Result<()> {
-    if let Some(commit) = &opts.sel4_baseline {
-        install_sel4_kernel(opts, prefix, &commit)
+impl KernelOptions {
+    /// The `git clone` depth args for the reL4 sources, none for a full clone
+    fn depth_args(&self) -> Vec<String> {
+        if self.full_clone {
+            vec![]
+        } else {
+            vec!["--depth".to_string(), self.depth.to_string()]
+        }
+    }
+
+    /// The url to fetch a GitHub repo from, on the git mirror if one is set
+    fn repo_url(&self, url: &str) -> String {
+        match (&self.git_mirror, url.strip_prefix("https://github.com/")) {
+            (Some(mirror), Some(path)) => format!("{}/{}", mirror.trim_end_matches('/'), path),
+            _ => url.to_string(),
+        }
+    }
+
+    /// Point the kernel loader `cargo install` at the shared target dir and cargo home, if set
+    fn cargo_env(&self, command: &mut Command) {
+        if let Some(target_dir) = &self.cargo_target_dir {
+            command.env("CARGO_TARGET_DIR", target_dir);
+        }
+        if let Some(cargo_home) = &self.cargo_home {
+            command.env("CARGO_HOME", cargo_home);
+        }
+        // `cargo install` builds in a temporary dir of its own
+        if self.tmp_dir != DEFAULT_TMP_DIR {
+            command.env("TMPDIR", &self.tmp_dir);
+        }
+    }
+
+    /// Where the bootable image of `--with-payload` is written
+    fn image_path(&self, prefix: &std::path::Path) -> std::path::PathBuf {
+        self.image.clone().unwrap_or_else(|| prefix.join("bin/image.elf"))
+    }
+
+    /// The path of the working dir `name` under `--tmp-dir`
+    fn work_dir(&self, name: &str) -> String {
+        work_dir(&self.tmp_dir, name)
+    }
+
+    /// The ARM CPU model to build for
+    fn arm_cpu(&self) -> String {
+        self.arm_cpu.clone().unwrap_or_else(|| platform_spec(self.platform).arm_cpu.unwrap_or_default().to_string())
+    }
+
+    /// The target triple of the reL4 kernel binary, adjusted by `--riscv-ext`
+    fn bin_target(&self) -> &'static str {
+        self.riscv_ext.and_then(|ext| self.platform.riscv_target(ext)).unwrap_or(platform_spec(self.platform).bin_target)
+    }
+
+    /// The target triple of the kernel loader, adjusted by `--riscv-ext`
+    fn loader_target(&self) -> &'static str {
+        self.riscv_ext.and_then(|ext| self.platform.riscv_target(ext)).unwrap_or(platform_spec(self.platform).loader_target)
+    }
+
+    /// Whether the sources are re-cloned, by `--force-clone` or `--force`
+    fn force_clone(&self) -> bool {
+        self.force || self.force_clone
+    }
+
+    /// Whether the kernel loader `cargo install` gets `--force`, by `--force-loader` or `--force`
+    fn force_loader(&self) -> bool {
+        self.force || self.force_loader
+    }
+
+    /// The cross compiler prefix to build with, the option wins over the platform default
+    fn cross_prefix(&self) -> String {
+        match &self.cross_compiler_prefix {
+            Some(cross_prefix) => cross_prefix.clone(),
+            None => platform_spec(self.platform).cross_prefix.to_string(),
+        }
+    }
+}
+
+/// The source commits a kernel was built from, and its ELF with the debug symbols
+struct BuiltKernel {
+    rel4: Option<String>,
+    sel4: Option<String>,
+    elf: Option<std::path::PathBuf>,
+}
+
+/// Copy the kernel ELF out of the build dir, which the next build or platform reuses, to
+/// `<prefix>/support/kernel.elf`. None when the build left no ELF there
+fn install_kernel_elf(build_elf: &std::path::Path, prefix: &str) -> anyhow::Result<Option<std::path::PathBuf>> {
+    if !exec::dry_run() && !build_elf.is_file() {
+        return Ok(None);
+    }
+    let installed = std::path::Path::new(prefix).join("support/kernel.elf");
+    exec::create_dir_all(installed.parent().ok_or(InstallError::InvalidPath("install"))?)?;
+    exec::copy(build_elf, &installed)?;
+    Ok(Some(installed))
+}
+
+/// Install the kernel, loader and image the options ask for, for `opts.platform` into prefix,
+/// returning the `--format json` report of the install
+fn install_platform(opts: &KernelOptions, prefix: &str, sources: &mut Option<SourcePaths>) -> anyhow::Result<InstallReport> {
+    let _lock = lock::acquire(prefix)?;
+    if opts.loader_only && !opts.payload_tool_only && !std::path::Path::new(prefix).join("libsel4/include").is_dir() {
+        exec::warn(format_args!("no kernel is installed under {}, the loader build needs its libseL4", prefix));
+    }
+    // Not a tool check, --skip-tool-check leaves it on
+    check_free_space(opts, prefix)?;
+    if !opts.skip_tool_check {
+        check_toolchains(opts)?;
+    }
+    let mut summary = Vec::new();
+    let kernel = if opts.loader_only {
+        None
     } else {
-        install_rel4_kernel(opts, prefix)
+        run_phase(opts, &mut summary, "kernel", || {
+            let kernel = install_kernel(opts, prefix, sources)?;
+            if let Some(expected) = &opts.expect_sha256 {
+                verify_sha256(&std::path::Path::new(prefix).join("bin").join(&opts.kernel_name), expected)?;
+            }
+            Ok(kernel)
+        })?
+    };
+    if !opts.no_loader {
+        run_phase(opts, &mut summary, "loader", || install_kernel_loader(opts, prefix))?;
+    }
+    if let Some(payload) = &opts.with_payload {
+        run_phase(opts, &mut summary, "image", || build_loader_image(opts, prefix, payload))?;
+    }
+    let failed = summary.iter().filter(|phase| phase.error.is_some()).count();
+    if failed == 0 {
+        verify_installation(opts, prefix)?;
+    }
+    // The manifest describes the kernel, a loader only install keeps the previous one
+    let manifest = match kernel {
+        Some(kernel) => Some(write_manifest(opts, prefix, kernel)?),
+        None => None,
+    };
+    if failed == 0 {
+        print_summary(opts, prefix, manifest.as_ref());
     }
+    if failed == 0 || opts.continue_on_error {
+        for phase in &summary {
+            let elapsed = format!("{:.1}s", phase.elapsed.as_secs_f64());
+            match &phase.error {
+                Some(error) => exec::report(format_args!("{:<20}{} after {}: {:#}", phase.name, exec::paint("failed", exec::RED), elapsed, error)),
+                None => exec::report(format_args!("{:<20}{} in {}", phase.name, exec::paint("ok", exec::GREEN), elapsed)),
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(InstallError::PhasesFailed { failed, total: summary.len() }.into());
+    }
+    Ok(InstallReport {
+        platform: opts.platform.to_string(),
+        prefix: prefix.to_string(),
+        artifacts: expected_artifacts(opts, prefix),
+        loader_rev: (!opts.no_loader).then(|| opts.loader_rev.clone()),
+        mode: manifest.as_ref().map(|manifest| manifest.mode.clone()),
+        rel4_kernel_commit: manifest.as_ref().and_then(|manifest| manifest.rel4_kernel_commit.clone()),
+        sel4_kernel_commit: manifest.and_then(|manifest| manifest.sel4_kernel_commit),
+    })
 }
 
-/// Install seL4 kernel
-fn install_sel4_kernel(opts: &KernelOptions, prefix: &str, commit: &str) -> anyhow::Result<()> {
-    let path = "/tmp/seL4_kernel";
-    if std::fs::remove_dir_all(path).is_err() {
-        // Do nothing if the directory does not exist
+/// Install kernel, seL4 or reL4. The sources are acquired by the first platform built
+fn install_kernel(opts: &KernelOptions, prefix: &str, sources: &mut Option<SourcePaths>) -> anyhow::Result<BuiltKernel> {
+    if opts.arm_cpu.is_some() && !opts.platform.is_arm() {
+        exec::warn(format_args!("--arm-cpu is ignored on non ARM platform {}", opts.platform));
+    }
+    if opts.hypervisor && !opts.platform.is_arm() {
+        return Err(InstallError::HypervisorUnsupported(opts.platform.to_string()).into());
+    }
+    if let Some(ext) = opts.riscv_ext {
+        if opts.platform.riscv_target(ext).is_none() {
+            return Err(InstallError::RiscvExtUnsupported { ext: ext.name(), platform: opts.platform.to_string() }.into());
+        }
+    }
+    if !opts.skip_tool_check {
+        check_cross_compiler(&opts.cross_prefix(), opts.platform)?;
     }
 
-    let mut exec = Command::new("git");
-    let command = exec.args(&[
-        "clone",
-        "https://github.com/seL4/seL4.git",
-        path,
-    ]);
+    let sources = match sources {
+        Some(sources) => sources,
+        None => sources.insert(ensure_sources(opts)?),
+    };
+    if opts.sel4_baseline.is_some() {
+        install_sel4_kernel(opts, prefix, sources)
+    } else {
+        install_rel4_kernel(opts, prefix, sources)
+    }
+}
 
-    let mut attempts = 0;
-    while !command.status()?.success() && attempts < 3 {
-        attempts += 1;
-        eprintln!("seL4 git clone failed. Retrying... (attempt {}/{})", attempts, 3);
+/// The seL4 ref `--sel4-baseline` names, `latest` being the tip of master
+fn sel4_baseline_ref(baseline: &str) -> &str {
+    if baseline == "latest" {
+        "master"
+    } else {
+        baseline
     }
+}
+
+/// Whether the ref may be a commit hash, full or abbreviated to at least 7 digits like
+/// `git log --oneline` shows, rather than a branch or tag. `git clone --branch` takes neither
+fn is_commit_hash(reference: &str) -> bool {
+    (7..=40).contains(&reference.len()) && reference.chars().all(|c| c.is_ascii_hexdigit())
+}
 
-    let checkout_command = Command::new("git")
-        .args(&["checkout", commit])
-        .current_dir(path)
-        .status()?;
-    if !checkout_command.success() {
-        return Err(anyhow::anyhow!("Failed to checkout specific commit"));
+/// Where the kernel sources are, acquired once however many platforms are built
+struct SourcePaths {
+    /// rel4-integral, none for `--sel4-baseline`
+    rel4_kernel: Option<String>,
+    /// seL4_c_impl, or seL4 for `--sel4-baseline`
+    sel4_kernel: std::path::PathBuf,
+}
+
+/// Clone the kernel sources, or check the local ones, for the builds of every platform
+fn ensure_sources(opts: &KernelOptions) -> anyhow::Result<SourcePaths> {
+    if let Some(baseline) = &opts.sel4_baseline {
+        return ensure_sel4_sources(opts, sel4_baseline_ref(baseline));
+    }
+    check_local_paths(opts)?;
+
+    let clone_dir = opts.work_dir(REL4_KERNEL_DIR);
+    let rel4_kernel_dir = 
+    if let Some(local_path) = &opts.local {
+        local_path.as_str()
+    } else {
+        let path = clone_dir.as_str();
+        let cloned = if opts.offline {
+            require_clone(path)?;
+            false
+        } else if opts.force_clone() || !std::path::Path::new(path).exists() {
+            exec::confirm_remove(std::path::Path::new(path))?;
+            if exec::remove_dir_all(path).is_err() {
+                // Do nothing if the directory does not exist
+            }
+
+            let depth_args = opts.depth_args();
+            let url = opts.repo_url(&opts.rel4_repo);
+            let mut clone_args = vec![url.as_str(), path, "--config", "advice.detachedHead=false"];
+            // A commit is checked out after cloning the default branch, `--branch` only takes refs
+            if opts.rel4_commit.is_none() {
+                clone_args.extend(["--branch", &opts.branch]);
+            }
+            clone_args.extend(depth_args.iter().map(String::as_str));
+            exec::phase("Cloning rel4-integral", || git_clone_with_retries(&clone_args, opts.clone_retries))?;
+            true
+        } else {
+            false
+        };
+
+        if let Some(commit) = &opts.rel4_commit {
+            git_checkout(path, commit, !opts.offline)?;
+        }
+        if cloned {
+            fix_home_version(opts.home_fixup, path)?;
+        }
+
+        path
+    };
+    apply_patches(rel4_kernel_dir, &opts.patch)?;
+
+    let build_sel4_dir = 
+    if let Some(local_sel4_path) = &opts.local_sel4 {
+        std::path::PathBuf::from(local_sel4_path)
+    } else if let Some(local_path) = &opts.local {
+        std::path::PathBuf::from(local_path).join("../kernel")
+    } else {
+        let path = &opts.work_dir(SEL4_KERNEL_DIR);
+        if opts.offline {
+            require_clone(path)?;
+        } else if opts.force_clone() || !std::path::Path::new(path).exists() {
+            exec::confirm_remove(std::path::Path::new(path))?;
+            if exec::remove_dir_all(path).is_err() {
+                // Do nothing if the directory does not exist
+            }
+
+            let depth_args = opts.depth_args();
+            let url = opts.repo_url(&opts.sel4_repo);
+            let mut clone_args = vec![url.as_str(), path, "--config", "advice.detachedHead=false"];
+            if let Some(branch) = &opts.sel4_c_impl_branch {
+                clone_args.extend(["--branch", branch]);
+            }
+            clone_args.extend(depth_args.iter().map(String::as_str));
+            exec::phase("Cloning seL4_c_impl", || git_clone_with_retries(&clone_args, opts.clone_retries))?;
+        }
+        if let Some(commit) = &opts.sel4_c_impl_commit {
+            git_checkout(path, commit, !opts.offline)?;
+        }
+        std::path::PathBuf::from(path)
+    };
+
+    let build_sel4_dir = cloned_kernel_dir(build_sel4_dir)?;
+    Ok(SourcePaths { rel4_kernel: Some(rel4_kernel_dir.to_string()), sel4_kernel: build_sel4_dir })
+}
+
+/// Clone seL4, `reference` is a commit or the branch or tag whose tip is built
+fn ensure_sel4_sources(opts: &KernelOptions, reference: &str) -> anyhow::Result<SourcePaths> {
+    let path = &opts.work_dir(SEL4_KERNEL_DIR);
+    if opts.offline {
+        require_clone(path)?;
+        git_checkout(path, reference, false)?;
+    } else {
+        exec::confirm_remove(std::path::Path::new(path))?;
+        if exec::remove_dir_all(path).is_err() {
+            // Do nothing if the directory does not exist
+        }
+
+        let url = opts.repo_url("https://github.com/seL4/seL4.git");
+        if is_commit_hash(reference) {
+            exec::phase("Cloning seL4", || git_clone_with_retries(&[&url, path], opts.clone_retries))?;
+            git_checkout(path, reference, true)?;
+        } else {
+            exec::phase("Cloning seL4", || git_clone_with_retries(&[&url, path, "--branch", reference], opts.clone_retries))?;
+        }
     }
 
     let build_sel4_dir = std::path::PathBuf::from(path);
 
-    let build_sel4_dir = std::fs::canonicalize(build_sel4_dir)?;
+    let build_sel4_dir = cloned_kernel_dir(build_sel4_dir)?;
+    Ok(SourcePaths { rel4_kernel: None, sel4_kernel: build_sel4_dir })
+}
+
+/// Build and install the seL4 kernel for the platform
+fn install_sel4_kernel(opts: &KernelOptions, prefix: &str, sources: &SourcePaths) -> anyhow::Result<BuiltKernel> {
+    let build_sel4_dir = sources.sel4_kernel.clone();
     let sel4_build_path = build_sel4_dir.join("build");
 
-    let install_prefix_flag = format!("-DCMAKE_INSTALL_PREFIX={}", prefix);
-    let args: Vec<&str> = match opts.platform.as_str() {
-        "spike" => {
-            vec![
-                "-DCROSS_COMPILER_PREFIX=riscv64-unknown-linux-gnu-",
-                &install_prefix_flag,
-                "-DKernelArch=riscv",
-                "-DKernelPlatform=spike",
-                "-DKernelSel4Arch=riscv64",
-                "-DKernelVerificationBuild=OFF",
-                "-G", "Ninja",
-                "-S", ".",
-                "-B", sel4_build_path.to_str().unwrap(),
-            ]
-        },
-        "qemu-arm-virt" => {
-            vec![
-                "-DCROSS_COMPILER_PREFIX=aarch64-linux-gnu-",
-                "-DKernelAllowSMCCalls=ON",
-                &install_prefix_flag,
-                "-DKernelArmExportPCNTUser=ON",
-                "-DKernelArmExportPTMRUser=ON",
-                "-DARM_CPU=cortex-a57",
-                "-DKernelArch=arm",
-                "-DKernelArmHypervisorSupport=OFF",
-                "-DKernelPlatform=qemu-arm-virt",
-                "-DKernelSel4Arch=aarch64",
-                "-DKernelVerificationBuild=OFF",
-                "-G", "Ninja",
-                "-S", ".",
-                "-B", sel4_build_path.to_str().unwrap(),
-            ]
-        },
-        _ => return Err(anyhow::anyhow!("Unsupported platform")),
-        
-    };
+    prepare_build_dir(&sel4_build_path, opts.platform, opts.clean_build, false)?;
+    let command = sel4_cmake_command(opts, prefix, &build_sel4_dir)?;
+    cmake_configure(command, &sel4_build_path, opts.platform, opts.keep_failed_build, false)?;
 
-    let status = Command::new("cmake")
-        .args(args)
-        .current_dir(build_sel4_dir.clone())
-        .status()?;
+    let status = exec::phase("Building kernel (ninja)", || ninja(&build_sel4_dir, "all", opts.jobs))?;
     if !status.success() {
-        return Err(anyhow::anyhow!("Failed to configure project with CMake"));
+        return Err(InstallError::NinjaBuild { phase: "build project" }.into());
     }
 
-    let status = Command::new("ninja")
-        .args(&["-C", "build", "all"])
-        .current_dir(build_sel4_dir.clone())
-        .status()?;
+    let status = exec::phase("Installing kernel (ninja)", || ninja(&build_sel4_dir, "install", opts.jobs))?;
     if !status.success() {
-        return Err(anyhow::anyhow!("Failed to build project with Ninja"));
+        return Err(InstallError::NinjaBuild { phase: "install project" }.into());
     }
 
-    let status = Command::new("ninja")
-        .args(&["-C", "build", "install"])
-        .current_dir(build_sel4_dir)
-        .status()?;
-    if !status.success() {
-        return Err(anyhow::anyhow!("Failed to install project with Ninja"));
+    let elf = install_kernel_elf(&sel4_build_path.join("kernel.elf"), prefix)?;
+    Ok(BuiltKernel { rel4: None, sel4: git_head(&build_sel4_dir), elf })
+}
+
+/// The cmake args for configuring the upstream seL4 kernel on the given platform
+fn sel4_cmake_args(platform: Platform, cross_prefix: &str, arm_cpu: &str, prefix: &str, build_path: &std::path::Path) -> anyhow::Result<Vec<String>> {
+    let build_path = build_path.to_str().ok_or(InstallError::InvalidPath("build"))?;
+    let mut args: Vec<String> = cross_compiler_flag(cross_prefix).into_iter().collect();
+    args.push(format!("-DCMAKE_INSTALL_PREFIX={}", prefix));
+    args.extend(platform_spec(platform).cmake_extra.iter().map(|flag| flag.to_string()));
+    if platform.is_arm() {
+        args.push(format!("-DARM_CPU={}", arm_cpu));
+    }
+    args.extend(["-DKernelVerificationBuild=OFF", "-G", "Ninja", "-S", ".", "-B", build_path].map(String::from));
+    Ok(args)
+}
+
+/// The cmake configure of the upstream seL4 kernel in `dir`, for `--sel4-baseline` builds.
+/// The reL4 build gets `--mcs` and `--nofastpath` through its xtask instead
+fn sel4_cmake_command(opts: &KernelOptions, prefix: &str, dir: &std::path::Path) -> anyhow::Result<Command> {
+    let mut args = sel4_cmake_args(opts.platform, &opts.cross_prefix(), &opts.arm_cpu(), prefix, &dir.join("build"))?;
+    if opts.mcs {
+        args.push("-DKernelIsMCS=ON".to_string());
+    }
+    if opts.nofastpath {
+        args.push("-DKernelFastpath=OFF".to_string());
     }
+    let mut command = Command::new("cmake");
+    command.args(args).args(kernel_option_flags(opts)).args(&opts.cmake_arg).current_dir(dir);
+    Ok(command)
+}
 
+/// The cmake configure of seL4_c_impl in `dir`, against the reL4 kernel
+fn rel4_cmake_command(opts: &KernelOptions, prefix: &str, dir: &std::path::Path) -> anyhow::Result<Command> {
+    let build_path = dir.join("build");
+    let build_path = build_path.to_str().ok_or(InstallError::InvalidPath("build"))?;
+    let mut args: Vec<String> = cross_compiler_flag(&opts.cross_prefix()).into_iter().collect();
+    args.push(format!("-DCMAKE_INSTALL_PREFIX={}", prefix));
+    args.push(format!("-DREL4_KERNEL={}", if opts.bin { "TRUE" } else { "FALSE" }));
+    let mut extra = platform_spec(opts.platform).rel4_cmake_extra.iter();
+    while let Some(flag) = extra.next() {
+        match (*flag, &opts.sel4_config) {
+            ("-C", Some(settings)) => {
+                extra.next();
+                args.push("-C".to_string());
+                args.push(settings.display().to_string());
+            }
+            _ => args.push(flag.to_string()),
+        }
+    }
+    // A platform without a kernel settings file of its own takes `--sel4-config` on top of its flags
+    if let Some(settings) = opts.sel4_config.as_ref().filter(|_| !platform_spec(opts.platform).rel4_cmake_extra.contains(&"-C")) {
+        args.push("-C".to_string());
+        args.push(settings.display().to_string());
+    }
+    if opts.platform.is_arm() {
+        args.push(format!("-DARM_CPU={}", opts.arm_cpu()));
+    }
+    args.extend(["-G", "Ninja", "-S", ".", "-B", build_path].map(String::from));
+    let mut command = Command::new("cmake");
+    command.args(args).args(kernel_option_flags(opts)).args(&opts.cmake_arg).current_dir(dir);
+    Ok(command)
+}
+
+/// Print the cmake configure `install kernel` would run, without cloning or building anything
+fn dump_cmake_command(opts: &KernelOptions, prefix: &str) -> anyhow::Result<()> {
+    let command = if opts.sel4_baseline.is_some() {
+        sel4_cmake_command(opts, prefix, std::path::Path::new(&opts.work_dir(SEL4_KERNEL_DIR)))?
+    } else {
+        let dir = match (&opts.local_sel4, &opts.local) {
+            (Some(local_sel4_path), _) => std::path::PathBuf::from(local_sel4_path),
+            (None, Some(local_path)) => std::path::PathBuf::from(local_path).join("../kernel"),
+            (None, None) => std::path::PathBuf::from(opts.work_dir(SEL4_KERNEL_DIR)),
+        };
+        let dir = std::fs::canonicalize(&dir).unwrap_or(dir);
+        rel4_cmake_command(opts, prefix, &dir)?
+    };
+    println!("{}", exec::display(&command));
     Ok(())
 }
 
-/// Install rel4 kernel stuff
-/// If Binary mode is enabled, reL4 kernel build kernel.elf and install it
-/// If Lib mode is enabled, reL4 kernel build librustlib.a for seL4 kernel
-fn install_rel4_kernel(opts: &KernelOptions, prefix: &str) -> anyhow::Result<()> {
-    let rel4_kernel_dir = 
-    if let Some(local_path) = &opts.local {
-        local_path.as_str()
+/// The cmake flags derived from the kernel options, shared by the seL4 and reL4 builds
+fn kernel_option_flags(opts: &KernelOptions) -> Vec<String> {
+    // Only the options given are passed, they come after the `-C` kernel settings and override
+    // them. A reused build dir keeps the values of an earlier build, `--clean-build` drops them
+    let mut flags = Vec::new();
+    if opts.hypervisor && opts.platform.is_arm() {
+        flags.push("-DKernelArmHypervisorSupport=ON".to_string());
+    }
+    if opts.smp > 1 {
+        flags.push(format!("-DKernelMaxNumNodes={}", opts.smp));
+        flags.push("-DKernelEnableSMPSupport=ON".to_string());
+    }
+    if let Some(ext) = opts.riscv_ext {
+        flags.extend(ext.cmake_flags().iter().map(|flag| flag.to_string()));
+    }
+    if opts.kernel_debug || opts.kernel_release {
+        flags.push(format!("-DKernelDebugBuild={}", on_off(opts.kernel_debug)));
+        flags.push(format!("-DKernelPrinting={}", on_off(opts.kernel_debug)));
+    }
+    if opts.benchmark != Benchmark::None {
+        flags.push(format!("-DKernelBenchmarks={}", opts.benchmark.cmake_value()));
+        // The benchmarks read the cycle counter from userspace
+        if opts.platform.is_arm() {
+            flags.push("-DKernelArmExportPMUUser=ON".to_string());
+        }
+    }
+    flags
+}
+
+/// The cmake value of a switch
+fn on_off(on: bool) -> &'static str {
+    if on {
+        "ON"
     } else {
-        let path = "/tmp/rel4_kernel";
-        if opts.force || !std::path::Path::new(path).exists() {
-            if std::fs::remove_dir_all(path).is_err() {
-                // Do nothing if the directory does not exist
-            }
+        "OFF"
+    }
+}
+
+/// The `-DCROSS_COMPILER_PREFIX` flag, none when building with the host compiler
+fn cross_compiler_flag(cross_prefix: &str) -> Option<String> {
+    if cross_prefix.is_empty() {
+        None
+    } else {
+        Some(format!("-DCROSS_COMPILER_PREFIX={}", cross_prefix))
+    }
+}
+
+/// Fail early when the cross compiler is missing, instead of halfway through the CMake configure.
+/// Dry runs only warn, as nothing is built
+fn check_cross_compiler(cross_prefix: &str, platform: Platform) -> anyhow::Result<()> {
+    let compiler = format!("{}gcc", cross_prefix);
+    if tool_available(&compiler) {
+        return Ok(());
+    }
+    let err = InstallError::CrossCompilerMissing { compiler, platform: platform.to_string() };
+    if exec::dry_run() {
+        exec::warn(&err);
+        return Ok(());
+    }
+    Err(err.into())
+}
+
+/// Whether `tool --version` runs successfully
+pub(crate) fn tool_available(tool: &str) -> bool {
+    Command::new(tool).arg("--version").output().is_ok_and(|output| output.status.success())
+}
+
+/// The toolchains listed by `rustup toolchain list`, empty if rustup is unavailable
+pub(crate) fn installed_toolchains() -> Vec<String> {
+    rustup_list(&["toolchain", "list"])
+}
+
+/// The lines `rustup` prints for the args, empty if it fails
+fn rustup_list(args: &[&str]) -> Vec<String> {
+    Command::new("rustup").args(args).output().ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).lines().map(String::from).collect())
+        .unwrap_or_default()
+}
 
-            let mut exec = Command::new("git");
-            let command = exec
-                .args(&["clone", "https://github.com/reL4team2/rel4-integral.git", path, 
-                        "--config", "advice.detachedHead=false", "--depth", "1", "--branch", &opts.branch]);
-            let mut attempts = 0;
-            while !command.status()?.success() && attempts < 3 {
-                attempts += 1;
-                eprintln!("rel4-integral git clone failed. Retrying... (attempt {}/{})", attempts, 3);
+/// Check that the toolchains the install builds with are there, with their target and rust-src.
+/// `--auto-toolchain` installs what is missing, otherwise the rustup commands doing it are
+/// printed. Dry runs only warn
+fn check_toolchains(opts: &KernelOptions) -> anyhow::Result<()> {
+    // The target is none for the host only payload tool, which needs neither target nor rust-src
+    let mut required = Vec::new();
+    // The seL4 baseline is a C kernel, only its loader is built with Rust
+    if !opts.loader_only && opts.sel4_baseline.is_none() {
+        required.push((opts.kernel_toolchain.as_str(), Some(opts.bin_target())));
+    }
+    if !opts.no_loader {
+        required.push((opts.loader_toolchain.as_str(), (!opts.payload_tool_only).then(|| opts.loader_target())));
+    }
+
+    let installed = installed_toolchains();
+    let mut problems = Vec::new();
+    let mut fixes: Vec<Vec<&str>> = Vec::new();
+    for (toolchain, target) in required {
+        if !installed.iter().any(|installed| installed.starts_with(toolchain)) {
+            problems.push(format!("toolchain {} is not installed", toolchain));
+            let mut fix = vec!["toolchain", "install", toolchain, "--profile", "minimal"];
+            if let Some(target) = target {
+                fix.extend(["--component", "rust-src", "--target", target]);
             }
+            fixes.push(fix);
+            continue;
+        }
+        let Some(target) = target else {
+            continue;
+        };
+        if !rustup_list(&["target", "list", "--installed", "--toolchain", toolchain]).iter().any(|line| line == target) {
+            problems.push(format!("toolchain {} lacks target {}", toolchain, target));
+            fixes.push(vec!["target", "add", target, "--toolchain", toolchain]);
+        }
+        // build-std builds core from the sources of the component, cargo fails obscurely without it
+        if !rustup_list(&["component", "list", "--installed", "--toolchain", toolchain]).iter().any(|line| line.starts_with("rust-src")) {
+            problems.push(format!("toolchain {} lacks the rust-src component build-std needs", toolchain));
+            fixes.push(vec!["component", "add", "rust-src", "--toolchain", toolchain]);
+        }
+    }
+    if problems.is_empty() {
+        return Ok(());
+    }
 
-            // fix home version bug
-            let status = Command::new("cargo").args(&["update", "home@0.5.11", "--precise", "0.5.5"]).current_dir(path).status()?;
+    if opts.auto_toolchain {
+        for (problem, args) in problems.iter().zip(&fixes) {
+            let status = exec::phase(&format!("Fixing Rust {}", problem), || exec::status(Command::new("rustup").args(args), "rustup"))?;
             if !status.success() {
-                return Err(anyhow::anyhow!("Failed to update home version"));
+                return Err(InstallError::ToolchainInstall(args.join(" ")).into());
             }
         }
-    
-        path
+        return Ok(());
+    }
+    let commands = fixes.iter().map(|args| format!("rustup {}", args.join(" "))).collect();
+    let err = InstallError::ToolchainsMissing { problems, commands };
+    if exec::dry_run() {
+        exec::warn(&err);
+        return Ok(());
+    }
+    Err(err.into())
+}
+
+/// Rough space the clones, builds and loader cargo target take under the tmp dir
+const ESTIMATED_BUILD_SPACE: u64 = 4 << 30;
+/// Rough space the installed kernel, libseL4 and loader take under prefix
+const ESTIMATED_PREFIX_SPACE: u64 = 100 << 20;
+
+/// Check the free space of the tmp dir and prefix filesystems before spending minutes on a
+/// build that runs out of it. Below `--min-free-space` aborts, below the estimate warns.
+/// Dry runs only warn
+fn check_free_space(opts: &KernelOptions, prefix: &str) -> anyhow::Result<()> {
+    for (path, estimate) in [(opts.tmp_dir.as_str(), ESTIMATED_BUILD_SPACE), (prefix, ESTIMATED_PREFIX_SPACE)] {
+        // The dirs may not exist yet, their closest existing parent is on the same filesystem
+        let Some(existing) = std::path::Path::new(path).ancestors().find(|dir| dir.exists()) else {
+            continue;
+        };
+        let Some(available) = fs2::available_space(existing).ok() else {
+            continue;
+        };
+        let gib = |bytes: u64| bytes as f64 / (1u64 << 30) as f64;
+        match opts.min_free_space {
+            Some(min) if available < min << 30 => {
+                let err = InstallError::DiskSpace { path: path.into(), available: format!("{:.1}", gib(available)), required: min };
+                if !exec::dry_run() {
+                    return Err(err.into());
+                }
+                exec::warn(&err);
+            }
+            Some(_) => {}
+            None if available < estimate => {
+                exec::warn(format_args!("only {:.1} GiB free for {}, the build may need about {:.1} GiB", gib(available), path, gib(estimate)));
+            }
+            None => {}
+        }
+    }
+    Ok(())
+}
+
+/// Run `git clone` with the given args, which start with the repo url and the destination path.
+/// A failed attempt removes the partial destination and waits 1s, 2s, 4s, ... before retrying,
+/// unless the remote has no `--branch` of that name
+fn git_clone_with_retries(args: &[&str], attempts: u32) -> anyhow::Result<()> {
+    let repo = args.first().copied().unwrap_or_default();
+    for attempt in 1..=attempts {
+        if exec::status(Command::new("git").arg("clone").args(args), "git")?.success() {
+            return Ok(());
+        }
+        if let Some(path) = args.get(1) {
+            if exec::remove_dir_all(path).is_err() {
+                // Do nothing if the directory does not exist
+            }
+        }
+        // A mistyped branch fails every attempt the same way, it is not worth retrying
+        if let Some(branch) = args.iter().position(|&arg| arg == "--branch").and_then(|index| args.get(index + 1)) {
+            let status = exec::status(Command::new("git").args(["ls-remote", "--exit-code", repo, branch]), "git")?;
+            if status.code() == Some(2) {
+                return Err(InstallError::RefNotFound { repo: repo.to_string(), reference: branch.to_string() }.into());
+            }
+        }
+        if attempt < attempts {
+            let delay = 1u64 << (attempt - 1).min(6);
+            eprintln!("git clone of {} failed. Retrying in {}s... (attempt {}/{})", repo, delay, attempt, attempts);
+            std::thread::sleep(std::time::Duration::from_secs(delay));
+        }
+    }
+    Err(InstallError::CloneFailed { repo: repo.to_string(), attempts }.into())
+}
+
+/// Checkout `reference` in the cloned repo at `dir`. When the clone lacks it and `fetch`
+/// is allowed, fetch the tags and then the reference itself from origin before giving up,
+/// the clone is kept
+fn git_checkout(dir: &str, reference: &str, fetch: bool) -> anyhow::Result<()> {
+    let checkout = |reference: &str| exec::status(Command::new("git").args(["checkout", reference]).current_dir(dir), "git");
+    if checkout(reference)?.success() {
+        return Ok(());
+    }
+    if !fetch {
+        return Err(InstallError::CheckoutFailed { reference: reference.to_string(), dir: dir.to_string() }.into());
+    }
+
+    // A failed fetch is a network failure, not a missing reference
+    let fetch = |args: &[&str], fetched: &str| -> anyhow::Result<()> {
+        if !exec::status(Command::new("git").arg("fetch").args(args).current_dir(dir), "git")?.success() {
+            return Err(InstallError::FetchFailed { reference: fetched.to_string(), dir: dir.to_string() }.into());
+        }
+        Ok(())
     };
+    eprintln!("git checkout {} failed, fetching tags and retrying", reference);
+    fetch(&["--tags"], "tags")?;
+    if checkout(reference)?.success() {
+        return Ok(());
+    }
+
+    fetch(&["origin", reference], reference)?;
+    if checkout("FETCH_HEAD")?.success() {
+        return Ok(());
+    }
+
+    Err(InstallError::CheckoutFailed { reference: reference.to_string(), dir: dir.to_string() }.into())
+}
+
+/// `git apply` the patches in `dir` in the order given. A patch that is already applied, from
+/// an earlier install into the same clone, is skipped
+fn apply_patches(dir: &str, patches: &[std::path::PathBuf]) -> anyhow::Result<()> {
+    for patch in patches {
+        let patch = std::path::absolute(patch)?;
+        let applied = !exec::dry_run() && Command::new("git").args(["apply", "--reverse", "--check"]).arg(&patch).current_dir(dir)
+            .output().is_ok_and(|output| output.status.success());
+        if applied {
+            eprintln!("{} is already applied in {}, skipping", patch.display(), dir);
+            continue;
+        }
+        if !exec::status(Command::new("git").arg("apply").arg(&patch).current_dir(dir), "git")?.success() {
+            return Err(InstallError::PatchFailed { patch, dir: dir.to_string() }.into());
+        }
+    }
+    Ok(())
+}
+
+/// Whether to pin the `home` crate of rel4-integral back to 0.5.5
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum HomeFixup {
+    /// Only when Cargo.lock resolves home to 0.5.11
+    Auto,
+    Always,
+    Never,
+}
+
+/// The cargo profile the reL4 kernel is built with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Profile {
+    Debug,
+    Release,
+}
+
+impl Profile {
+    /// The dir under `target/<triple>` the profile builds into
+    fn dir(self) -> &'static str {
+        match self {
+            Profile::Debug => "debug",
+            Profile::Release => "release",
+        }
+    }
+}
+
+/// The seL4 benchmark instrumentation the kernel is built with, `KernelBenchmarks`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Benchmark {
+    None,
+    Generic,
+    #[value(name = "track_kernel_entries")]
+    TrackKernelEntries,
+    #[value(name = "track_utilisation")]
+    TrackUtilisation,
+}
+
+impl Benchmark {
+    /// The `KernelBenchmarks` cmake value of the mode
+    fn cmake_value(self) -> &'static str {
+        match self {
+            Benchmark::None => "none",
+            Benchmark::Generic => "generic",
+            Benchmark::TrackKernelEntries => "track_kernel_entries",
+            Benchmark::TrackUtilisation => "track_utilisation",
+        }
+    }
+}
+
+/// Fix the home version bug, home 0.5.11 does not build with the kernel toolchain.
+/// The fixup is best effort, newer branches may not depend on that version at all
+fn fix_home_version(fixup: HomeFixup, dir: &str) -> anyhow::Result<()> {
+    let needed = match fixup {
+        HomeFixup::Always => true,
+        HomeFixup::Never => false,
+        HomeFixup::Auto => {
+            exec::dry_run() || std::fs::read_to_string(std::path::Path::new(dir).join("Cargo.lock"))
+                .is_ok_and(|lock| lock.contains("name = \"home\"\nversion = \"0.5.11\""))
+        }
+    };
+    if !needed {
+        return Ok(());
+    }
+
+    let status = exec::status(Command::new("cargo").args(["update", "home@0.5.11", "--precise", "0.5.5"]).current_dir(dir), "cargo")?;
+    if !status.success() {
+        exec::warn("failed to pin home to 0.5.5, building with the locked version");
+    }
+    Ok(())
+}
+
+/// Check that `--local` is a rel4-integral checkout and that seL4_c_impl is where it is
+/// expected, before any tool runs in a wrong dir. `--sel4-config` is checked too unless it
+/// is relative to a seL4_c_impl that is not cloned yet
+fn check_local_paths(opts: &KernelOptions) -> anyhow::Result<()> {
+    if let Some(settings) = &opts.sel4_config {
+        let sel4_dir = match (&opts.local_sel4, &opts.local) {
+            (Some(local_sel4_path), _) => Some(std::path::PathBuf::from(local_sel4_path)),
+            (None, Some(local_path)) => Some(std::path::PathBuf::from(local_path).join("../kernel")),
+            (None, None) => None,
+        };
+        let settings = match sel4_dir {
+            _ if settings.is_absolute() => Some(settings.clone()),
+            Some(dir) => Some(dir.join(settings)),
+            None => None,
+        };
+        if let Some(settings) = settings.filter(|settings| !exec::dry_run() && !settings.is_file()) {
+            return Err(InstallError::SettingsMissing(settings).into());
+        }
+    }
+
+    let Some(local) = &opts.local else {
+        return Ok(());
+    };
+    let local = std::path::Path::new(local);
+    let invalid = |reason: String| InstallError::InvalidLocal { path: local.to_path_buf(), reason };
+    // A config file can still set both
+    if !opts.patch.is_empty() {
+        return Err(invalid("--patch would modify the checkout, apply the patches to it instead".to_string()).into());
+    }
+    if !local.is_dir() {
+        return Err(invalid("no such directory".to_string()).into());
+    }
+    if !local.join("Cargo.toml").is_file() {
+        return Err(invalid("no Cargo.toml, it is not a rel4-integral checkout".to_string()).into());
+    }
+    if !local.join("xtask").is_dir() {
+        exec::warn(format_args!("{} has no xtask dir, `cargo xtask build` may fail", local.display()));
+    }
+
+    if opts.local_sel4.is_none() {
+        let sel4 = local.join("../kernel");
+        if !sel4.is_dir() {
+            return Err(invalid(format!("seL4_c_impl is expected at {}, pass --local-sel4 if it is elsewhere", sel4.display())).into());
+        }
+    }
+    Ok(())
+}
+
+/// The canonical path of the kernel sources, with a clear error when the clone is missing
+fn cloned_kernel_dir(dir: std::path::PathBuf) -> anyhow::Result<std::path::PathBuf> {
+    if !exec::dry_run() && !dir.exists() {
+        return Err(InstallError::KernelMissing(dir).into());
+    }
+    Ok(exec::canonicalize(dir)?)
+}
+
+/// In offline mode the clones are never made, so they must already be there
+fn require_clone(path: &str) -> anyhow::Result<()> {
+    if !std::path::Path::new(path).exists() {
+        return Err(InstallError::CloneMissing(path.to_string()).into());
+    }
+    Ok(())
+}
+
+/// Remove the CMake build dir when asked to, or when its cache was configured for
+/// another platform, so stale cache entries can not leak into the new build. A build dir in
+/// a `local` checkout is only removed once confirmed
+fn prepare_build_dir(build_path: &std::path::Path, platform: Platform, clean: bool, local: bool) -> anyhow::Result<()> {
+    if !build_path.exists() {
+        return Ok(());
+    }
+    if !clean {
+        match cached_platform(build_path) {
+            Some(cached) if cached != platform_spec(platform).cmake_platform() => {
+                exec::report(format_args!("{} was configured for {}, cleaning it", build_path.display(), cached));
+            }
+            _ => return Ok(()),
+        }
+    }
+    if local {
+        exec::confirm_remove(build_path)?;
+    }
+    exec::remove_dir_all(build_path)?;
+    Ok(())
+}
+
+/// Run the cmake configure of the kernel. A failed configure leaves a cache the next run fails
+/// on too, so the build dir is removed unless `keep_failed_build`. In a `local` checkout it is
+/// only removed once confirmed, and kept when it can not be
+fn cmake_configure(mut command: Command, build_path: &std::path::Path, platform: Platform, keep_failed_build: bool, local: bool) -> anyhow::Result<()> {
+    let status = exec::phase("Configuring CMake", || exec::status(&mut command, "cmake"))?;
+    if status.success() {
+        return Ok(());
+    }
+    let confirmed = || !local || exec::confirm_remove(build_path).inspect_err(|err| exec::warn(err)).is_ok();
+    if !keep_failed_build && build_path.exists() && confirmed() {
+        exec::remove_dir_all(build_path)?;
+        eprintln!("Removed {} of the failed configure, --keep-failed-build keeps it", build_path.display());
+    }
+    Err(InstallError::CmakeConfigure { platform: platform.to_string() }.into())
+}
+
+/// The `KernelPlatform` and `KernelSel4Arch` the build dir was configured with, as `platform/sel4_arch`
+fn cached_platform(build_path: &std::path::Path) -> Option<String> {
+    let cache = std::fs::read_to_string(build_path.join("CMakeCache.txt")).ok()?;
+    let entry = |key: &str| {
+        cache.lines().find_map(|line| {
+            let (name, value) = line.split_once('=')?;
+            (name.split(':').next() == Some(key)).then(|| value.to_string())
+        })
+    };
+    Some(format!("{}/{}", entry("KernelPlatform")?, entry("KernelSel4Arch")?))
+}
+
+/// Run ninja on the `build` dir under `dir` for the given target
+fn ninja(dir: &std::path::Path, target: &str, jobs: Option<usize>) -> anyhow::Result<std::process::ExitStatus> {
+    let mut command = Command::new("ninja");
+    command.args(["-C", "build", target]).current_dir(dir);
+    if let Some(jobs) = jobs {
+        command.arg("-j").arg(jobs.to_string());
+    }
+    exec::status(&mut command, "ninja")
+}
+
+/// Install rel4 kernel stuff
+/// If Binary mode is enabled, reL4 kernel build kernel.elf and install it
+/// If Lib mode is enabled, reL4 kernel build librustlib.a for seL4 kernel
+fn install_rel4_kernel(opts: &KernelOptions, prefix: &str, sources: &SourcePaths) -> anyhow::Result<BuiltKernel> {
+    let rel4_kernel_dir = sources.rel4_kernel.as_deref().ok_or(InstallError::InvalidPath("reL4 kernel"))?;
+    let build_sel4_dir = sources.sel4_kernel.clone();
 
     let mut command = Command::new("rustup");
     let mut args = vec![
         "run",
-        "nightly-2024-02-01",
+        &opts.kernel_toolchain,
         "cargo",
         "xtask",
         "build",
         "--rust-only",
     ];
 
-    match opts.platform.as_str() {
-        "spike" => {
-            args.push("--platform");
-            args.push("spike");
-        }
-        "qemu-arm-virt" => {
-            args.push("--platform");
-            args.push("qemu-arm-virt");
-            args.push("-s");
-            args.push("on");
-            args.push("--arm-pcnt");
-            args.push("--arm-ptmr");
-        }
-        _ => {
-            return Err(anyhow::anyhow!("Unsupported platform: {}", opts.platform));
-        }
-    }
+    args.extend(platform_spec(opts.platform).xtask_args);
 
     if opts.mcs {
         args.push("--mcs");
         args.push("on");
     }
 
+    if opts.hypervisor {
+        args.push("--hypervisor");
+        args.push("on");
+    }
+
     if opts.nofastpath {
         args.push("--nofastpath");
     }
@@ -241,156 +1290,608 @@ fn install_rel4_kernel(opts: &KernelOptions, prefix: &str) -> anyhow::Result<()>
     if opts.bin {
         args.push("--bin");
     }
+
+    if opts.profile == Profile::Debug {
+        args.push("--debug");
+    }
+
+    args.extend(opts.xtask_arg.iter().map(String::as_str));
     
-    if !command.args(&args).current_dir(rel4_kernel_dir).status()?.success() {
-        return Err(anyhow::anyhow!("Failed to build reL4 kernel"));
+    let status = exec::phase("Building reL4 kernel (xtask)", || exec::status(command.args(&args).current_dir(rel4_kernel_dir), "xtask"))?;
+    if !status.success() {
+        return Err(InstallError::BuildFailed("reL4 kernel").into());
     }
 
     if opts.bin {
-        let target: String = match opts.platform.as_str() {
-            "spike" => {"riscv64imac-unknown-none-elf".to_string()},
-            "qemu-arm-virt" => {"aarch64-unknown-none-softfloat".to_string()},
-            _ => return Err(anyhow::anyhow!("Unsupported platform")),
-        };
-        let kernel_path = std::path::PathBuf::from(&rel4_kernel_dir).join(format!("target/{}/release/rel4_kernel", target));
-        let install_path = std::path::PathBuf::from(&prefix).join("bin/kernel.elf");
-        std::fs::create_dir_all(install_path.parent().ok_or_else(|| anyhow::anyhow!("Invalid install path"))?)?;
-        std::fs::copy(&kernel_path, &install_path)?;
+        let kernel_path = std::path::PathBuf::from(rel4_kernel_dir).join(format!("target/{}/{}/rel4_kernel", opts.bin_target(), opts.profile.dir()));
+        let install_path = std::path::PathBuf::from(&prefix).join("bin").join(&opts.kernel_name);
+        if !exec::dry_run() && !kernel_path.is_file() {
+            let dir = kernel_path.parent().map(std::path::Path::to_path_buf).unwrap_or_default();
+            return Err(InstallError::ArtifactsMissing { project: "reL4 kernel", dir }.into());
+        }
+        exec::create_dir_all(install_path.parent().ok_or(InstallError::InvalidPath("install"))?)?;
+        exec::copy(&kernel_path, &install_path)?;
+        if !exec::dry_run() {
+            let size = std::fs::metadata(&install_path)?.len();
+            exec::report(format_args!("Installed {} -> {} ({} bytes, sha256 {})", kernel_path.display(), install_path.display(), size, sha256_file(&install_path)?));
+        }
     }
 
-    let build_sel4_dir = 
-    if let Some(local_path) = &opts.local {
-        std::path::PathBuf::from(local_path).join("../kernel")
-    } else {
-        let path = "/tmp/seL4_kernel";
-        if opts.force || !std::path::Path::new(path).exists() {
-            if std::fs::remove_dir_all(path).is_err() {
-                // Do nothing if the directory does not exist
-            }
+    let sel4_build_path = build_sel4_dir.join("build");
 
-            let mut exec = Command::new("git");
-            let command = exec.args(&["clone", "https://github.com/reL4team2/seL4_c_impl.git", path, "--config", "advice.detachedHead=false"]);
-            let mut attempts = 0;
-            while !command.status()?.success() && attempts < 3 {
-                attempts += 1;
-                eprintln!("seL4_c_impl git clone failed. Retrying... (attempt {}/{})", attempts, 3);
-            }
+    if let Some(settings) = &opts.sel4_config {
+        let settings = build_sel4_dir.join(settings);
+        if !exec::dry_run() && !settings.is_file() {
+            return Err(InstallError::SettingsMissing(settings).into());
         }
-        std::path::PathBuf::from(path)
-    };
+    }
+    prepare_build_dir(&sel4_build_path, opts.platform, opts.clean_build, opts.local.is_some() || opts.local_sel4.is_some())?;
+    let command = rel4_cmake_command(opts, prefix, &build_sel4_dir)?;
+    cmake_configure(command, &sel4_build_path, opts.platform, opts.keep_failed_build, opts.local.is_some() || opts.local_sel4.is_some())?;
 
-    let build_sel4_dir = std::fs::canonicalize(build_sel4_dir)?;    
-    let sel4_build_path = build_sel4_dir.join("build");
+    let status = exec::phase("Building kernel (ninja)", || ninja(&build_sel4_dir, "all", opts.jobs))?;
+    if !status.success() {
+        return Err(InstallError::NinjaBuild { phase: "build project" }.into());
+    }
+
+    let status = exec::phase("Installing kernel (ninja)", || ninja(&build_sel4_dir, "install", opts.jobs))?;
+    if !status.success() {
+        return Err(InstallError::NinjaBuild { phase: "install project" }.into());
+    }
 
-    let rel4_kernel_flag = format!("-DREL4_KERNEL={}", if opts.bin { "TRUE" } else { "FALSE" });
-    let install_prefix_flag = format!("-DCMAKE_INSTALL_PREFIX={}", prefix);
-    let args: Vec<&str> = match opts.platform.as_str() {
-        "spike" => {
-            vec![
-                "-DCROSS_COMPILER_PREFIX=riscv64-unknown-linux-gnu-",
-                &install_prefix_flag, &rel4_kernel_flag,
-                "-C", "./kernel-settings-riscv64.cmake",
-                "-G", "Ninja",
-                "-S", ".",
-                "-B", sel4_build_path.to_str().unwrap(),
-            ]
-        },
-        "qemu-arm-virt" => {
-            vec![
-                "-DCROSS_COMPILER_PREFIX=aarch64-linux-gnu-",
-                "-DKernelAllowSMCCalls=ON",
-                &install_prefix_flag, &rel4_kernel_flag,
-                "-DKernelArmExportPCNTUser=ON",
-                "-DKernelArmExportPTMRUser=ON",
-                "-C", "./kernel-settings-aarch64.cmake",
-                "-G", "Ninja",
-                "-S", ".",
-                "-B", sel4_build_path.to_str().unwrap(),
-            ]
-        },
-        _ => return Err(anyhow::anyhow!("Unsupported platform")),
-        
+    // In lib mode the reL4 kernel is linked into the seL4_c_impl build
+    let elf = if opts.bin {
+        Some(std::path::PathBuf::from(&prefix).join("bin").join(&opts.kernel_name))
+    } else {
+        install_kernel_elf(&sel4_build_path.join("kernel.elf"), prefix)?
     };
+    Ok(BuiltKernel { rel4: git_head(std::path::Path::new(rel4_kernel_dir)), sel4: git_head(&build_sel4_dir), elf })
+}
 
-    let status = Command::new("cmake")
-        .args(args)
-        .current_dir(build_sel4_dir.clone())
-        .status()?;
-    if !status.success() {
-        return Err(anyhow::anyhow!("Failed to configure project with CMake"));
+/// How an install phase went, for the summary printed at the end
+struct PhaseResult {
+    name: &'static str,
+    elapsed: std::time::Duration,
+    error: Option<anyhow::Error>,
+}
+
+/// Run the install phase `name`, timing it into `summary`. With `--continue-on-error` a failure
+/// is recorded in `summary` and none is returned so the remaining phases still run, otherwise
+/// the failure is returned
+fn run_phase<T>(
+    opts: &KernelOptions,
+    summary: &mut Vec<PhaseResult>,
+    name: &'static str,
+    phase: impl FnOnce() -> anyhow::Result<T>,
+) -> anyhow::Result<Option<T>> {
+    let start = std::time::Instant::now();
+    let result = phase();
+    let elapsed = start.elapsed();
+    match result {
+        Err(err) if !opts.continue_on_error => Err(err),
+        Err(err) => {
+            summary.push(PhaseResult { name, elapsed, error: Some(err) });
+            Ok(None)
+        }
+        result => {
+            summary.push(PhaseResult { name, elapsed, error: None });
+            result.map(Some)
+        }
     }
+}
 
-    let status = Command::new("ninja")
-        .args(&["-C", "build", "all"])
-        .current_dir(build_sel4_dir.clone())
-        .status()?;
-    if !status.success() {
-        return Err(anyhow::anyhow!("Failed to build project with Ninja"));
+/// Print what `install kernel` installed, the phase timings follow it
+fn print_summary(opts: &KernelOptions, prefix: &str, manifest: Option<&Manifest>) {
+    let on_off = |on: bool| if on { "on" } else { "off" }.to_string();
+    let unknown = || "-".to_string();
+    let mut rows = vec![
+        ("platform", opts.platform.to_string()),
+        ("mode", manifest.map_or_else(|| "loader only".to_string(), |manifest| manifest.mode.clone())),
+        ("mcs", on_off(opts.mcs)),
+        ("fastpath", on_off(!opts.nofastpath)),
+        ("prefix", prefix.to_string()),
+    ];
+    if let Some(manifest) = manifest {
+        rows.push(("reL4 kernel commit", manifest.rel4_kernel_commit.clone().unwrap_or_else(unknown)));
+        rows.push(("seL4 kernel commit", manifest.sel4_kernel_commit.clone().unwrap_or_else(unknown)));
+    }
+    if !opts.no_loader {
+        rows.push(("loader rev", opts.loader_rev.clone()));
+    }
+    for (name, value) in rows {
+        exec::report(format_args!("{:<20}{}", name, value));
     }
+}
 
-    let status = Command::new("ninja")
-        .args(&["-C", "build", "install"])
-        .current_dir(build_sel4_dir)
-        .status()?;
-    if !status.success() {
-        return Err(anyhow::anyhow!("Failed to install project with Ninja"));
+/// The files and dirs the install steps the options ask for put under prefix
+fn expected_artifacts(opts: &KernelOptions, prefix: &str) -> Vec<std::path::PathBuf> {
+    let prefix = std::path::Path::new(prefix);
+    let mut artifacts = Vec::new();
+    if !opts.loader_only {
+        if opts.bin {
+            artifacts.push(prefix.join("bin").join(&opts.kernel_name));
+        }
+        artifacts.push(prefix.join("libsel4/include"));
     }
+    if !opts.no_loader {
+        if !opts.payload_tool_only {
+            artifacts.push(prefix.join("bin/sel4-kernel-loader"));
+        }
+        artifacts.push(prefix.join("bin/sel4-kernel-loader-add-payload"));
+    }
+    if opts.with_payload.is_some() {
+        artifacts.push(opts.image_path(prefix));
+    }
+    artifacts
+}
 
+/// Fail unless everything the install steps were asked for landed under prefix, catching
+/// builds and copies that failed without saying so. Files must not be empty
+fn verify_installation(opts: &KernelOptions, prefix: &str) -> anyhow::Result<()> {
+    if exec::dry_run() {
+        return Ok(());
+    }
+    let missing: Vec<_> = expected_artifacts(opts, prefix)
+        .into_iter()
+        .filter(|path| !path.metadata().is_ok_and(|metadata| metadata.is_dir() || metadata.len() > 0))
+        .collect();
+    if !missing.is_empty() {
+        return Err(InstallError::InstallIncomplete(missing).into());
+    }
     Ok(())
 }
 
+/// Record what was installed under prefix
+fn write_manifest(opts: &KernelOptions, prefix: &str, kernel: BuiltKernel) -> anyhow::Result<Manifest> {
+    let mode = if opts.sel4_baseline.is_some() {
+        "sel4-baseline"
+    } else if opts.bin {
+        "bin"
+    } else {
+        "lib"
+    };
+    let installed_at = time::now();
+    let manifest = Manifest {
+        platform: opts.platform.to_string(),
+        mode: mode.to_string(),
+        mcs: opts.mcs,
+        rel4_kernel_commit: kernel.rel4,
+        sel4_kernel_commit: kernel.sel4,
+        kernel_elf: kernel.elf,
+        image: opts.with_payload.as_ref().map(|_| std::path::absolute(opts.image_path(std::path::Path::new(prefix)))).transpose()?,
+        loader_rev: opts.loader_rev.clone(),
+        installed_at,
+    };
+    manifest.write(prefix)?;
+    Ok(manifest)
+}
+
+/// The `--format json` result of `install kernel`
+#[derive(Debug, serde::Serialize)]
+struct InstallReport {
+    platform: String,
+    /// none for `--loader-only` installs, which build no kernel
+    mode: Option<String>,
+    prefix: String,
+    artifacts: Vec<std::path::PathBuf>,
+    rel4_kernel_commit: Option<String>,
+    sel4_kernel_commit: Option<String>,
+    loader_rev: Option<String>,
+}
+
+/// The `--format json` result of a multi-platform install, one object for the whole run.
+/// A failed platform gets the error object of `main`
+fn platforms_report(results: &[(Platform, anyhow::Result<InstallReport>)]) -> anyhow::Result<serde_json::Value> {
+    let mut reports = Vec::new();
+    for (platform, result) in results {
+        reports.push(match result {
+            Err(err) => {
+                let install_error = err.downcast_ref::<InstallError>();
+                serde_json::json!({
+                    "platform": platform.name(),
+                    "error": install_error.map(InstallError::kind),
+                    "message": format!("{:#}", err),
+                    "exit_code": install_error.map_or(1, InstallError::exit_code),
+                })
+            }
+            std::result::Result::Ok(report) => serde_json::to_value(report)?,
+        });
+    }
+    Ok(serde_json::json!({ "platforms": reports }))
+}
+
+/// `git rev-parse HEAD` of the repo at dir, none if it can not be resolved
+pub(crate) fn git_head(dir: &std::path::Path) -> Option<String> {
+    let output = Command::new("git").args(["rev-parse", "HEAD"]).current_dir(dir).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Install sel4-kernel-loader-add-payload and sel4-kernel-loader. The two are independent
+/// crates and are built at the same time unless `--sequential` is given
 fn install_kernel_loader(opts: &KernelOptions, prefix: &str) -> anyhow::Result<()> {
-    let mut cmd = Command::new("rustup");
-    let url: String = "https://github.com/reL4team2/rust-sel4.git".into();
-    let rev: String = "642b58d807c5e5fc22f0c15d1467d6bec328faa9".into();
+    let url = opts.repo_url(&opts.loader_repo);
+    let rev = &opts.loader_rev;
+    // The cc crate picks the C compiler of the loader target from `CC_<target>`
+    let loader_target = opts.loader_target();
 
-    let mut args: Vec<&str> = vec![
+    let mut add_payload_args: Vec<&str> = vec![
         "run",
-        "nightly-2024-08-01",
+        &opts.loader_toolchain,
         "cargo",
         "install",
         "--git", url.as_str(),
         "--rev", rev.as_str(),
-        "--root".into(), prefix,
-        "sel4-kernel-loader-add-payload".into(),
+        "--root", prefix,
+        "sel4-kernel-loader-add-payload",
     ];
-
-    if opts.force {
-        args.push("--force");
-    }
-
-    cmd.env_remove("RUSTUP_TOOLCHAIN").env_remove("CARGO").args(&args).status().expect("failed install sel4-kernel-loader-add-payload");
-    
-    let target: String = match opts.platform.as_str() {
-        "spike" => {"riscv64imac-unknown-none-elf".to_string()},
-        "qemu-arm-virt" => {"aarch64-unknown-none".to_string()},
-        _ => return Err(anyhow::anyhow!("Unsupported platform")),
-    };
-    let mut cmd = Command::new("rustup");
-    let mut args: Vec<&str>  = vec![
+    let mut loader_args: Vec<&str> = vec![
         "run",
-        "nightly-2024-08-01",
+        &opts.loader_toolchain,
         "cargo",
         "install",
         "-Z", "build-std=core,compiler_builtins",
         "-Z", "build-std-features=compiler-builtins-mem",
-        "--target", target.as_str(),
+        "--target", loader_target,
         "--git", url.as_str(),
         "--rev", rev.as_str(),
-        "--root".into(), prefix,
-        "sel4-kernel-loader".into(),
+        "--root", prefix,
+        "sel4-kernel-loader",
     ];
+    for args in [&mut add_payload_args, &mut loader_args] {
+        if opts.force_loader() {
+            args.push("--force");
+        }
+        if opts.offline {
+            args.push("--offline");
+        }
+    }
+
+    let mut add_payload = Command::new("rustup");
+    opts.cargo_env(&mut add_payload);
+    add_payload.env_remove("RUSTUP_TOOLCHAIN").env_remove("CARGO").args(&add_payload_args);
+    let mut loader = Command::new("rustup");
+    opts.cargo_env(&mut loader);
+    loader.env_remove("RUSTUP_TOOLCHAIN")
+        .env_remove("CARGO")
+        .env("SEL4_PREFIX", prefix)
+        .env(format!("CC_{}", loader_target.replace('-', "_")), format!("{}gcc", opts.cross_prefix()))
+        .args(&loader_args);
+
+    let cargo_install = |command: &mut Command, krate: &'static str, tag: &str, args: &[&str]| -> anyhow::Result<()> {
+        let status = exec::status(command, tag)?;
+        if !status.success() {
+            return Err(InstallError::CargoInstall { krate, command: format!("rustup {}", args.join(" ")), status }.into());
+        }
+        Ok(())
+    };
+    // Dry runs stay sequential so the printed commands keep their order
+    if opts.payload_tool_only {
+        cargo_install(&mut add_payload, "sel4-kernel-loader-add-payload", "add-payload", &add_payload_args)?;
+    } else if opts.sequential || exec::dry_run() {
+        cargo_install(&mut add_payload, "sel4-kernel-loader-add-payload", "add-payload", &add_payload_args)?;
+        cargo_install(&mut loader, "sel4-kernel-loader", "loader", &loader_args)?;
+    } else {
+        let (add_payload_result, loader_result) = std::thread::scope(|scope| {
+            let add_payload = scope.spawn(|| cargo_install(&mut add_payload, "sel4-kernel-loader-add-payload", "add-payload", &add_payload_args));
+            let loader_result = cargo_install(&mut loader, "sel4-kernel-loader", "loader", &loader_args);
+            (add_payload.join(), loader_result)
+        });
+        let add_payload_result = add_payload_result.map_err(|_| InstallError::InstallPanicked("sel4-kernel-loader-add-payload"))?;
+        match (add_payload_result, loader_result) {
+            (Err(add_payload_err), Err(loader_err)) => {
+                eprintln!("Error: {}", loader_err);
+                return Err(add_payload_err);
+            }
+            (add_payload_result, loader_result) => add_payload_result.and(loader_result)?,
+        }
+    }
+
+    let binaries: &[&str] = if opts.payload_tool_only {
+        &["sel4-kernel-loader-add-payload"]
+    } else {
+        &["sel4-kernel-loader-add-payload", "sel4-kernel-loader"]
+    };
+    for binary in binaries {
+        let path = std::path::Path::new(prefix).join("bin").join(binary);
+        // A missing binary is reported by `verify_installation`
+        if !exec::dry_run() && path.exists() {
+            exec::report(format_args!("sha256 {}  {}", sha256_file(&path)?, path.display()));
+        }
+    }
+
+    Ok(())
+}
 
-    if opts.force {
-        args.push("--force");
+/// Embed the kernel and the app under prefix into the installed kernel loader,
+/// writing a bootable image
+fn build_loader_image(opts: &KernelOptions, prefix: &str, payload: &std::path::Path) -> anyhow::Result<()> {
+    let bin = std::path::Path::new(prefix).join("bin");
+    let image = opts.image_path(std::path::Path::new(prefix));
+    let status = exec::status(Command::new(bin.join("sel4-kernel-loader-add-payload"))
+        .arg("--loader").arg(bin.join("sel4-kernel-loader"))
+        .args(["--sel4-prefix", prefix])
+        .arg("--app").arg(payload)
+        .arg("-o").arg(&image), "add-payload")?;
+    if !status.success() {
+        return Err(InstallError::BuildFailed("loader image").into());
+    }
+    if !exec::dry_run() {
+        exec::report(format_args!("Bootable image written to {}", image.display()));
     }
+    Ok(())
+}
+
+/// The SHA-256 of the file, in hex
+fn sha256_file(path: &std::path::Path) -> anyhow::Result<String> {
+    use sha2::{Digest, Sha256};
+
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// Fail unless the file has the expected SHA-256
+fn verify_sha256(path: &std::path::Path, expected: &str) -> anyhow::Result<()> {
+    if exec::dry_run() {
+        exec::report(format_args!("# check that the sha256 of {} is {}", path.display(), expected));
+        return Ok(());
+    }
+    let actual = sha256_file(path)?;
+    if !actual.eq_ignore_ascii_case(expected.trim()) {
+        return Err(InstallError::ChecksumMismatch { path: path.to_path_buf(), expected: expected.to_string(), actual }.into());
+    }
+    exec::report(format_args!("sha256 {}  {}", actual, path.display()));
+    Ok(())
+}
+
+#[derive(Debug, Parser)]
+struct LinuxKitOptions {
+    /// The target platform to install
+    #[clap(default_value = "qemu-arm-virt", short, long)]
+    pub platform: Platform,
+    /// Local reL4-linux-kit path
+    #[clap(short = 'L', long, conflicts_with = "branch")]
+    pub local: Option<String>,
+    /// reL4-linux-kit branch
+    #[clap(long, default_value = "main")]
+    pub branch: String,
+    /// force install
+    #[clap(long)]
+    pub force: bool,
+    /// How many times a failed git clone is attempted, with a doubling delay from 1s between attempts
+    #[clap(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+    pub clone_retries: u32,
+}
+
+/// Install reL4-linux-kit
+/// The linux kit is built against the libseL4 under prefix, every ELF it produces
+/// is installed into `<prefix>/linux-kit`
+fn install_linux_kit(opts: &LinuxKitOptions, prefix: &str, tmp_dir: &str) -> anyhow::Result<()> {
+    // reL4-linux-kit only has xtask support for these platforms
+    if !matches!(opts.platform, Platform::Spike | Platform::QemuArmVirt) {
+        return Err(InstallError::UnsupportedPlatform(opts.platform.to_string()).into());
+    }
+    let target = platform_spec(opts.platform).bin_target;
+
+    let clone_dir = work_dir(tmp_dir, LINUX_KIT_DIR);
+    let linux_kit_dir =
+    if let Some(local_path) = &opts.local {
+        local_path.as_str()
+    } else {
+        let path = clone_dir.as_str();
+        if opts.force || !std::path::Path::new(path).exists() {
+            exec::confirm_remove(std::path::Path::new(path))?;
+            if exec::remove_dir_all(path).is_err() {
+                // Do nothing if the directory does not exist
+            }
+
+            git_clone_with_retries(&["https://github.com/reL4team2/reL4-linux-kit.git", path,
+                                    "--config", "advice.detachedHead=false", "--depth", "1", "--branch", &opts.branch], opts.clone_retries)?;
+        }
 
-    cmd.env_remove("RUSTUP_TOOLCHAIN")
+        path
+    };
+
+    let status = exec::status(Command::new("rustup")
+        .args(["run", LOADER_TOOLCHAIN, "cargo", "xtask", "build", "--platform", opts.platform.name()])
+        .env_remove("RUSTUP_TOOLCHAIN")
         .env_remove("CARGO")
         .env("SEL4_PREFIX", prefix)
-        .env("CC_aarch64_unknown_none", "aarch64-linux-gnu-gcc")
-        .args(&args)
-        .status().expect("failed install sel4-kernel-loader");
+        .current_dir(linux_kit_dir), "xtask")?;
+    if !status.success() {
+        return Err(InstallError::BuildFailed("reL4-linux-kit").into());
+    }
+
+    let build_path = std::path::PathBuf::from(linux_kit_dir).join(format!("target/{}/release", target));
+    let install_path = std::path::PathBuf::from(prefix).join("linux-kit");
+    exec::create_dir_all(&install_path)?;
+    if exec::dry_run() && !build_path.exists() {
+        exec::report(format_args!("# copy the ELF files in {} into {}", build_path.display(), install_path.display()));
+        return Ok(());
+    }
+
+    let mut installed = 0;
+    for entry in std::fs::read_dir(&build_path)? {
+        let path = entry?.path();
+        if !path.is_file() || !is_elf(&path)? {
+            continue;
+        }
+        let file_name = path.file_name().ok_or(InstallError::InvalidPath("artifact"))?;
+        exec::copy(&path, install_path.join(file_name))?;
+        installed += 1;
+    }
+    if installed == 0 {
+        return Err(InstallError::ArtifactsMissing { project: "reL4-linux-kit", dir: build_path }.into());
+    }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Check the ELF magic of a build artifact
+fn is_elf(path: &std::path::Path) -> anyhow::Result<bool> {
+    use std::io::Read;
+
+    let mut magic = [0u8; 4];
+    let mut file = std::fs::File::open(path)?;
+    if file.read_exact(&mut magic).is_err() {
+        return Ok(false);
+    }
+    Ok(magic == *b"\x7fELF")
+}
+
+#[derive(Debug, Parser)]
+struct LibSel4Options {
+    /// The target platform to install
+    #[clap(default_value = "qemu-arm-virt", short, long)]
+    pub platform: Platform,
+    /// Local seL4 kernel path
+    #[clap(short = 'L', long, conflicts_with = "branch")]
+    pub local: Option<String>,
+    /// seL4 kernel branch
+    #[clap(long, default_value = "master")]
+    pub branch: String,
+    /// force install
+    #[clap(long)]
+    pub force: bool,
+    /// How many times a failed git clone is attempted, with a doubling delay from 1s between attempts
+    #[clap(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+    pub clone_retries: u32,
+    /// Number of parallel ninja jobs, ninja's default when unset
+    #[clap(short, long)]
+    pub jobs: Option<usize>,
+    /// Cross compiler prefix, e.g. `aarch64-linux-gnu-`, the platform default when unset
+    #[clap(long)]
+    pub cross_compiler_prefix: Option<String>,
+}
+
+/// Install libseL4 only
+/// Configure the seL4 kernel the same way as the baseline build, but only build the
+/// libsel4 target and copy its headers and static library into `<prefix>/libsel4`
+fn install_libsel4(opts: &LibSel4Options, prefix: &str, tmp_dir: &str) -> anyhow::Result<()> {
+    let (arch, sel4_arch, plat, word_size) = platform_spec(opts.platform).libsel4_include_names;
+    let cross_prefix = match &opts.cross_compiler_prefix {
+        Some(cross_prefix) => cross_prefix.as_str(),
+        None => platform_spec(opts.platform).cross_prefix,
+    };
+
+    check_cross_compiler(cross_prefix, opts.platform)?;
+
+    let sel4_dir =
+    if let Some(local_path) = &opts.local {
+        std::path::PathBuf::from(local_path)
+    } else {
+        let path = &work_dir(tmp_dir, LIBSEL4_DIR);
+        if opts.force || !std::path::Path::new(path).exists() {
+            exec::confirm_remove(std::path::Path::new(path))?;
+            if exec::remove_dir_all(path).is_err() {
+                // Do nothing if the directory does not exist
+            }
+
+            git_clone_with_retries(&["https://github.com/seL4/seL4.git", path,
+                                    "--config", "advice.detachedHead=false", "--depth", "1", "--branch", &opts.branch], opts.clone_retries)?;
+        }
+        std::path::PathBuf::from(path)
+    };
+
+    let sel4_dir = cloned_kernel_dir(sel4_dir)?;
+    let sel4_build_path = sel4_dir.join("build");
+
+    prepare_build_dir(&sel4_build_path, opts.platform, false, opts.local.is_some())?;
+    let arm_cpu = platform_spec(opts.platform).arm_cpu.unwrap_or_default();
+    let args = sel4_cmake_args(opts.platform, cross_prefix, arm_cpu, prefix, &sel4_build_path)?;
+    let mut command = Command::new("cmake");
+    command.args(args).current_dir(&sel4_dir);
+    cmake_configure(command, &sel4_build_path, opts.platform, false, opts.local.is_some())?;
+
+    let status = ninja(&sel4_dir, "libsel4", opts.jobs)?;
+    if !status.success() {
+        return Err(InstallError::NinjaBuild { phase: "build libsel4" }.into());
+    }
+
+    // Merge the source and generated include dirs, the same layout `ninja install` produces
+    let install_path = std::path::PathBuf::from(prefix).join("libsel4");
+    let include_path = install_path.join("include");
+    let include_dirs = [
+        sel4_dir.join("libsel4/include"),
+        sel4_dir.join("libsel4/arch_include").join(arch),
+        sel4_dir.join("libsel4/sel4_arch_include").join(sel4_arch),
+        sel4_dir.join("libsel4/sel4_plat_include").join(plat),
+        sel4_dir.join("libsel4/mode_include").join(word_size),
+        sel4_build_path.join("libsel4/include"),
+        sel4_build_path.join("libsel4/arch_include").join(arch),
+        sel4_build_path.join("libsel4/sel4_arch_include").join(sel4_arch),
+        sel4_build_path.join("libsel4/autoconf"),
+        sel4_build_path.join("libsel4/gen_config"),
+        sel4_build_path.join("gen_config"),
+    ];
+    for dir in include_dirs.iter().filter(|dir| exec::dry_run() || dir.exists()) {
+        exec::copy_dir_all(dir, &include_path)?;
+    }
+
+    let lib_path = install_path.join("lib");
+    exec::create_dir_all(&lib_path)?;
+    exec::copy(sel4_build_path.join("libsel4/libsel4.a"), lib_path.join("libsel4.a"))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_commit_hash_takes_full_and_short_hashes() {
+        assert!(is_commit_hash("5e1cd3c"));
+        assert!(is_commit_hash("642b58d807c5e5fc22f0c15d1467d6bec328faa9"));
+        assert!(is_commit_hash("642B58D807C5"));
+    }
+
+    #[test]
+    fn platforms_report_holds_reports_and_errors() {
+        let report = InstallReport {
+            platform: "spike".to_string(),
+            mode: Some("lib".to_string()),
+            prefix: "/opt/rel4/spike".to_string(),
+            artifacts: vec!["/opt/rel4/spike/libsel4/include".into()],
+            rel4_kernel_commit: None,
+            sel4_kernel_commit: Some("5e1cd3c".to_string()),
+            loader_rev: None,
+        };
+        let results = [
+            (Platform::Spike, std::result::Result::Ok(report)),
+            (Platform::QemuArmVirt, Err(InstallError::CloneFailed { repo: "rel4".to_string(), attempts: 3 }.into())),
+        ];
+        assert_eq!(platforms_report(&results).unwrap(), serde_json::json!({
+            "platforms": [
+                {
+                    "platform": "spike",
+                    "mode": "lib",
+                    "prefix": "/opt/rel4/spike",
+                    "artifacts": ["/opt/rel4/spike/libsel4/include"],
+                    "rel4_kernel_commit": null,
+                    "sel4_kernel_commit": "5e1cd3c",
+                    "loader_rev": null,
+                },
+                {
+                    "platform": "qemu-arm-virt",
+                    "error": "CloneFailed",
+                    "message": "git clone of rel4 failed after 3 attempts",
+                    "exit_code": 3,
+                },
+            ],
+        }));
+    }
+
+    #[test]
+    fn is_commit_hash_rejects_refs() {
+        assert!(!is_commit_hash("master"));
+        assert!(!is_commit_hash("13.0.0"));
+        assert!(!is_commit_hash("5e1cd3"));
+        assert!(!is_commit_hash("642b58d807c5e5fc22f0c15d1467d6bec328faa9a"));
+        assert!(!is_commit_hash(""));
+    }
+}
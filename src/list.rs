@@ -0,0 +1,39 @@
+use clap::Parser;
+
+use crate::manifest::Manifest;
+use crate::time;
+
+#[derive(Debug, Parser)]
+pub(crate) struct ListOptions {
+    /// Print the raw install manifest
+    #[clap(long)]
+    pub json: bool,
+}
+
+/// Report what `install kernel` put under prefix, from its manifest
+pub(crate) fn list(opts: ListOptions, prefix: &str) -> anyhow::Result<()> {
+    let Some(manifest) = Manifest::read(prefix)? else {
+        println!("Nothing is installed under {}", prefix);
+        return Ok(());
+    };
+    if opts.json {
+        println!("{}", serde_json::to_string_pretty(&manifest)?);
+        return Ok(());
+    }
+
+    let unknown = || "-".to_string();
+    let rows = [
+        ("platform", manifest.platform.clone()),
+        ("mode", manifest.mode.clone()),
+        ("mcs", manifest.mcs.to_string()),
+        ("reL4 kernel commit", manifest.rel4_kernel_commit.clone().unwrap_or_else(unknown)),
+        ("seL4 kernel commit", manifest.sel4_kernel_commit.clone().unwrap_or_else(unknown)),
+        ("kernel elf", manifest.kernel_elf.as_ref().map_or_else(unknown, |elf| elf.display().to_string())),
+        ("loader rev", manifest.loader_rev.clone()),
+        ("installed at", time::format_utc(manifest.installed_at)),
+    ];
+    for (name, value) in rows {
+        println!("{:<20}{}", name, value);
+    }
+    Ok(())
+}
@@ -0,0 +1,32 @@
+use fs2::FileExt;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, Write};
+use std::path::Path;
+
+use crate::exec;
+
+/// The lock file under prefix, holding the pid of the running install
+const LOCK_FILE: &str = ".rel4-cli.lock";
+
+/// Lock prefix for the rest of the install, waiting for any other install into it to finish.
+/// The lock is released when the returned file is dropped, none in dry-run mode
+pub(crate) fn acquire(prefix: &str) -> anyhow::Result<Option<File>> {
+    if exec::dry_run() {
+        return Ok(None);
+    }
+
+    std::fs::create_dir_all(prefix)?;
+    let path = Path::new(prefix).join(LOCK_FILE);
+    let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)?;
+    if file.try_lock_exclusive().is_err() {
+        let mut pid = String::new();
+        file.read_to_string(&mut pid)?;
+        eprintln!("Another install into {} is in progress (pid {}), waiting for it to finish", prefix, pid.trim());
+        file.lock_exclusive()?;
+    }
+
+    file.set_len(0)?;
+    file.rewind()?;
+    writeln!(file, "{}", std::process::id())?;
+    Ok(Some(file))
+}
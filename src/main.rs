@@ -1,11 +1,87 @@
+mod clean;
+mod config;
+mod doctor;
+mod error;
+mod exec;
 mod install;
-use clap::Parser;
+mod list;
+mod lock;
+mod manifest;
+mod platform;
+mod run;
+mod time;
+mod uninstall;
+mod update;
+use clap::{CommandFactory, FromArgMatches, Parser};
 
 #[derive(Debug, Parser)]
+#[command(version, long_version = long_version(), after_help = "Exit codes:
+  1  any other failure
+  2  invalid command line or config file options
+  3  a git clone or fetch failed, worth retrying
+  4  a build step failed
+  5  a step ran longer than --timeout
+  6  a required tool is missing")]
 pub struct Options {
     /// The command to run
     #[clap(subcommand)]
     command: Command,
+    /// seL4 prefix path the kernel is installed into, shared by every command, e.g. `/workspace/.seL4`
+    /// in the dev container. The flag wins over `REL4_PREFIX`, which wins over the per-user default
+    #[clap(short = 'P', long = "prefix", id = "sel4_prefix", value_name = "PREFIX", visible_alias = "sel4-prefix", global = true, env = "REL4_PREFIX", default_value_t = install::default_prefix())]
+    prefix: String,
+    /// Where `install` makes its working clones and builds, e.g. a large disk when /tmp is a small tmpfs
+    #[clap(long, global = true, default_value = install::DEFAULT_TMP_DIR)]
+    tmp_dir: String,
+    /// Prefix each line of the build tools output with the tool name, e.g. `[cmake]`
+    #[clap(short, long, global = true)]
+    verbose: bool,
+    /// Print the commands and file operations as shell instead of running them
+    #[clap(long, global = true)]
+    dry_run: bool,
+    /// Hide the build tools output except for their errors, `--verbose` wins over it
+    #[clap(short, long, global = true)]
+    quiet: bool,
+    /// Also write the output of every build tool into this file, with a header per command
+    #[clap(long, global = true)]
+    log_file: Option<std::path::PathBuf>,
+    /// Kill any git, cmake, ninja or cargo step running longer than this many seconds
+    #[clap(long, global = true, value_name = "SECONDS")]
+    timeout: Option<u64>,
+    /// Print the environment variables each build tool gets set or unset before running it
+    #[clap(long, global = true)]
+    print_env: bool,
+    /// Delete existing clones without asking, needed when there is no terminal to ask on
+    #[clap(short, long, global = true)]
+    yes: bool,
+    /// Print the result of `install kernel` and any error as JSON, the human output goes to stderr
+    #[clap(long, global = true, value_enum, default_value_t = Format::Text)]
+    format: Format,
+    /// When to color the output, `auto` colors on a terminal unless `NO_COLOR` is set
+    #[clap(long, global = true, value_enum, default_value_t = clap::ColorChoice::Auto)]
+    color: clap::ColorChoice,
+}
+
+/// The `--version` output, with the CLI commit and the pinned defaults bug reports need
+fn long_version() -> &'static str {
+    static LONG_VERSION: std::sync::OnceLock<String> = std::sync::OnceLock::new();
+    LONG_VERSION.get_or_init(|| {
+        format!(
+            "{}\ncommit: {}\nkernel toolchain: {}\nloader toolchain: {}\nloader rev: {}",
+            env!("CARGO_PKG_VERSION"),
+            env!("REL4_CLI_GIT_HASH"),
+            install::KERNEL_TOOLCHAIN,
+            install::LOADER_TOOLCHAIN,
+            install::LOADER_REV,
+        )
+    })
+}
+
+/// How results and errors are printed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum Format {
+    Text,
+    Json,
 }
 
 #[derive(Debug, Parser)]
@@ -13,13 +89,129 @@ enum Command {
     /// Install develop dependency, such as reL4 kernel, reL4-linux-kit, libseL4
     #[command(about = "Install develop dependency, such as reL4 kernel, reL4-linux-kit, libseL4")]
     Install(install::InstallOptions),
+    /// Uninstall develop dependency installed by `install`
+    #[command(about = "Uninstall develop dependency installed by `install`")]
+    Uninstall(uninstall::UninstallOptions),
+    /// List the platforms `install kernel --platform` supports
+    #[command(about = "List the platforms `install kernel --platform` supports")]
+    Platforms,
+    /// Remove the working directories under `--tmp-dir` left behind by `install`
+    #[command(about = "Remove the working directories under `--tmp-dir` left behind by `install`")]
+    Clean,
+    /// Check that the host tools needed by `install` are available
+    #[command(about = "Check that the host tools needed by `install` are available")]
+    Doctor(doctor::DoctorOptions),
+    /// Fetch the kernel sources cloned by `install` and reset them to the remote head
+    #[command(about = "Fetch the kernel sources cloned by `install` and reset them to the remote head")]
+    Update(update::UpdateOptions),
+    /// Report the kernel installed under a prefix, from its install manifest
+    #[command(about = "Report the kernel installed under a prefix")]
+    List(list::ListOptions),
+    /// Boot the installed kernel in QEMU
+    #[command(about = "Boot the installed kernel in QEMU")]
+    Run(run::RunOptions),
+    /// Print the shell completion script, e.g. `rel4-cli completions bash > /etc/bash_completion.d/rel4-cli`
+    #[command(hide = true)]
+    Completions {
+        /// The shell to generate the completion script for
+        shell: clap_complete::Shell,
+    },
+}
+
+fn main() {
+    let matches = Options::command().try_get_matches().unwrap_or_else(|err| usage_error(err));
+    let opts = Options::from_arg_matches(&matches).unwrap_or_else(|err| usage_error(err));
+    exec::init(exec::Settings {
+        verbose: opts.verbose,
+        dry_run: opts.dry_run,
+        quiet: opts.quiet,
+        timeout: opts.timeout.map(std::time::Duration::from_secs),
+        print_env: opts.print_env,
+        yes: opts.yes,
+        json: opts.format == Format::Json,
+        color: color_enabled(opts.color),
+    });
+    let result = match &opts.log_file {
+        Some(log_file) => exec::open_log(log_file),
+        None => Ok(()),
+    };
+    if let Err(err) = result.and_then(|_| run(opts, &matches)) {
+        let install_error = err.downcast_ref::<error::InstallError>();
+        let code = install_error.map_or(1, error::InstallError::exit_code);
+        if exec::json() {
+            let error = serde_json::json!({
+                "error": install_error.map(error::InstallError::kind),
+                "message": format!("{:#}", err),
+                "exit_code": code,
+            });
+            eprintln!("{}", error);
+        } else {
+            eprintln!("{} {:?}", exec::paint("Error:", exec::RED), err);
+        }
+        std::process::exit(code);
+    }
+}
+
+/// Exit on a command line parse error, printed as the JSON error object when the args ask for
+/// `--format json`. Help and version output is left to clap
+fn usage_error(err: clap::Error) -> ! {
+    let args: Vec<String> = std::env::args().collect();
+    let json = args.iter().any(|arg| arg == "--format=json") || args.windows(2).any(|pair| pair == ["--format", "json"]);
+    if !json || !err.use_stderr() {
+        err.exit();
+    }
+
+    let error = serde_json::json!({
+        "error": "Usage",
+        "message": err.to_string().trim_end(),
+        "exit_code": err.exit_code(),
+    });
+    eprintln!("{}", error);
+    std::process::exit(err.exit_code());
 }
 
-fn main() -> anyhow::Result<()> {
-    let opts = Options::parse();
+/// Whether `--color` and `NO_COLOR` let the output be colored
+fn color_enabled(choice: clap::ColorChoice) -> bool {
+    use std::io::IsTerminal;
+    match choice {
+        clap::ColorChoice::Always => true,
+        clap::ColorChoice::Never => false,
+        clap::ColorChoice::Auto => {
+            std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+                && std::io::stdout().is_terminal()
+                && std::io::stderr().is_terminal()
+        }
+    }
+}
+
+fn run(opts: Options, matches: &clap::ArgMatches) -> anyhow::Result<()> {
     match opts.command {
         Command::Install(install_opts) => {
-            install::install(install_opts)?;
+            install::install(install_opts, &opts.prefix, &opts.tmp_dir, matches)?;
+        }
+        Command::Uninstall(uninstall_opts) => {
+            uninstall::uninstall(uninstall_opts, &opts.prefix)?;
+        }
+        Command::Platforms => {
+            platform::list_platforms();
+        }
+        Command::Clean => {
+            clean::clean(&opts.tmp_dir)?;
+        }
+        Command::Doctor(doctor_opts) => {
+            doctor::doctor(doctor_opts)?;
+        }
+        Command::Update(update_opts) => {
+            update::update(update_opts, &opts.tmp_dir)?;
+        }
+        Command::List(list_opts) => {
+            list::list(list_opts, &opts.prefix)?;
+        }
+        Command::Run(run_opts) => {
+            run::run(run_opts, &opts.prefix)?;
+        }
+        Command::Completions { shell } => {
+            clap_complete::generate(shell, &mut Options::command(), "rel4-cli", &mut std::io::stdout());
         }
     }
     Ok(())
@@ -0,0 +1,54 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::exec;
+
+/// The manifest file written under prefix after an install
+const MANIFEST_FILE: &str = ".rel4-cli-manifest.json";
+
+/// What `install kernel` put under prefix, with the resolved source commits
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Manifest {
+    pub platform: String,
+    /// `bin` or `lib` for reL4 kernels, `sel4-baseline` for the upstream seL4 kernel
+    pub mode: String,
+    pub mcs: bool,
+    /// `git rev-parse HEAD` of rel4-integral, none for baseline builds
+    pub rel4_kernel_commit: Option<String>,
+    /// `git rev-parse HEAD` of seL4_c_impl, or of seL4 for baseline builds
+    pub sel4_kernel_commit: Option<String>,
+    /// The kernel ELF with its debug symbols under prefix, for gdb, none in manifests of older installs
+    #[serde(default)]
+    pub kernel_elf: Option<PathBuf>,
+    /// The bootable loader image of `--with-payload`, none when the kernel was installed without one
+    #[serde(default)]
+    pub image: Option<PathBuf>,
+    /// The rust-sel4 rev the kernel loader was installed from
+    pub loader_rev: String,
+    /// Seconds since the Unix epoch
+    pub installed_at: u64,
+}
+
+impl Manifest {
+    pub(crate) fn path(prefix: &str) -> PathBuf {
+        Path::new(prefix).join(MANIFEST_FILE)
+    }
+
+    /// The manifest under prefix, none if nothing was installed there
+    pub(crate) fn read(prefix: &str) -> anyhow::Result<Option<Self>> {
+        let path = Self::path(prefix);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let content = std::fs::read_to_string(&path)?;
+        let manifest = serde_json::from_str(&content)
+            .map_err(|err| anyhow::anyhow!("Invalid manifest {}: {}", path.display(), err))?;
+        Ok(Some(manifest))
+    }
+
+    pub(crate) fn write(&self, prefix: &str) -> anyhow::Result<()> {
+        let content = serde_json::to_string_pretty(self)?;
+        exec::write(Self::path(prefix), content + "\n")?;
+        Ok(())
+    }
+}
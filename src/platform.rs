@@ -0,0 +1,270 @@
+use serde::{Deserialize, Serialize};
+
+/// The platforms the kernel can be installed for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Deserialize, Serialize)]
+pub(crate) enum Platform {
+    #[value(name = "spike")]
+    #[serde(rename = "spike")]
+    Spike,
+    #[value(name = "spike32")]
+    #[serde(rename = "spike32")]
+    Spike32,
+    #[value(name = "qemu-arm-virt")]
+    #[serde(rename = "qemu-arm-virt")]
+    QemuArmVirt,
+    // Raspberry Pi 4
+    #[value(name = "bcm2711")]
+    #[serde(rename = "bcm2711")]
+    Bcm2711,
+    // ODROID-C2
+    #[value(name = "odroidc2")]
+    #[serde(rename = "odroidc2")]
+    Odroidc2,
+    // pc99 is the seL4 name of the platform
+    #[value(name = "x86_64", alias = "pc99")]
+    #[serde(rename = "x86_64", alias = "pc99")]
+    X86_64,
+}
+
+/// The RISC-V ISA extensions the kernel and loader can be built with, picking the target triple
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Deserialize, Serialize)]
+pub(crate) enum RiscvExt {
+    #[value(name = "imac")]
+    #[serde(rename = "imac")]
+    Imac,
+    #[value(name = "imafc")]
+    #[serde(rename = "imafc")]
+    Imafc,
+    #[value(name = "gc")]
+    #[serde(rename = "gc")]
+    Gc,
+}
+
+impl RiscvExt {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            RiscvExt::Imac => "imac",
+            RiscvExt::Imafc => "imafc",
+            RiscvExt::Gc => "gc",
+        }
+    }
+
+    /// The cmake flags switching the floating point extensions of the kernel on or off
+    pub(crate) fn cmake_flags(self) -> &'static [&'static str] {
+        match self {
+            RiscvExt::Imac => &["-DKernelRiscvExtF=OFF", "-DKernelRiscvExtD=OFF"],
+            RiscvExt::Imafc => &["-DKernelRiscvExtF=ON", "-DKernelRiscvExtD=OFF"],
+            RiscvExt::Gc => &["-DKernelRiscvExtF=ON", "-DKernelRiscvExtD=ON"],
+        }
+    }
+}
+
+/// Everything the install steps need to know about a platform, kept in one place so the
+/// seL4, reL4 and loader builds can not drift apart
+pub(crate) struct PlatformSpec {
+    /// The default cross compiler prefix, empty for the host compiler
+    pub cross_prefix: &'static str,
+    /// The ARM CPU model used when `--arm-cpu` is not given, none for non ARM platforms
+    pub arm_cpu: Option<&'static str>,
+    /// The cmake flags selecting the platform in the upstream seL4 kernel
+    pub cmake_extra: &'static [&'static str],
+    /// The cmake flags selecting the platform in seL4_c_impl, built on the reL4 kernel settings files
+    pub rel4_cmake_extra: &'static [&'static str],
+    /// The target triple the reL4 kernel binary is built for
+    pub bin_target: &'static str,
+    /// The target triple the kernel loader is built for, the loader is built with
+    /// build-std so it does not need the softfloat target the kernel uses on aarch64
+    pub loader_target: &'static str,
+    /// The platform args of the reL4 `cargo xtask build`
+    pub xtask_args: &'static [&'static str],
+    /// The libsel4 include dir names, as (arch, sel4_arch, plat, word size)
+    pub libsel4_include_names: (&'static str, &'static str, &'static str, &'static str),
+    /// The QEMU system emulator, machine and CPU `run` boots the platform with, empty when
+    /// QEMU can not emulate the board
+    pub qemu_system: &'static str,
+    pub qemu_machine: &'static str,
+    pub qemu_cpu: &'static str,
+}
+
+impl PlatformSpec {
+    /// The `KernelPlatform/KernelSel4Arch` pair the platform is configured as in CMake
+    pub(crate) fn cmake_platform(&self) -> String {
+        let (_, sel4_arch, plat, _) = self.libsel4_include_names;
+        format!("{}/{}", plat, sel4_arch)
+    }
+}
+
+/// The spec of the platform, `-DARM_CPU` is left to the caller as it comes from `--arm-cpu`
+pub(crate) fn platform_spec(platform: Platform) -> PlatformSpec {
+    match platform {
+        Platform::Spike => PlatformSpec {
+            cross_prefix: "riscv64-unknown-linux-gnu-",
+            arm_cpu: None,
+            cmake_extra: &["-DKernelArch=riscv", "-DKernelPlatform=spike", "-DKernelSel4Arch=riscv64"],
+            rel4_cmake_extra: &["-C", "./kernel-settings-riscv64.cmake"],
+            bin_target: "riscv64imac-unknown-none-elf",
+            loader_target: "riscv64imac-unknown-none-elf",
+            xtask_args: &["--platform", "spike"],
+            libsel4_include_names: ("riscv", "riscv64", "spike", "64"),
+            qemu_system: "qemu-system-riscv64",
+            qemu_machine: "spike",
+            qemu_cpu: "rv64",
+        },
+        Platform::Spike32 => PlatformSpec {
+            cross_prefix: "riscv32-unknown-elf-",
+            arm_cpu: None,
+            cmake_extra: &["-DKernelArch=riscv", "-DKernelPlatform=spike", "-DKernelSel4Arch=riscv32"],
+            rel4_cmake_extra: &["-C", "./kernel-settings-riscv32.cmake"],
+            bin_target: "riscv32imac-unknown-none-elf",
+            loader_target: "riscv32imac-unknown-none-elf",
+            xtask_args: &["--platform", "spike32"],
+            libsel4_include_names: ("riscv", "riscv32", "spike", "32"),
+            qemu_system: "qemu-system-riscv32",
+            qemu_machine: "spike",
+            qemu_cpu: "rv32",
+        },
+        Platform::QemuArmVirt => PlatformSpec {
+            cross_prefix: "aarch64-linux-gnu-",
+            arm_cpu: Some("cortex-a57"),
+            cmake_extra: &[
+                "-DKernelAllowSMCCalls=ON",
+                "-DKernelArmExportPCNTUser=ON",
+                "-DKernelArmExportPTMRUser=ON",
+                "-DKernelArch=arm",
+                "-DKernelPlatform=qemu-arm-virt",
+                "-DKernelSel4Arch=aarch64",
+            ],
+            rel4_cmake_extra: &[
+                "-DKernelAllowSMCCalls=ON",
+                "-DKernelArmExportPCNTUser=ON",
+                "-DKernelArmExportPTMRUser=ON",
+                "-C", "./kernel-settings-aarch64.cmake",
+            ],
+            bin_target: "aarch64-unknown-none-softfloat",
+            loader_target: "aarch64-unknown-none",
+            xtask_args: &["--platform", "qemu-arm-virt", "-s", "on", "--arm-pcnt", "--arm-ptmr"],
+            libsel4_include_names: ("arm", "aarch64", "qemu-arm-virt", "64"),
+            qemu_system: "qemu-system-aarch64",
+            qemu_machine: "virt",
+            qemu_cpu: "cortex-a57",
+        },
+        // There is no cmake or xtask UART option for the board, the seL4 device tree picks the
+        // mini UART as stdout and the firmware only enables it with `enable_uart=1` in config.txt
+        Platform::Bcm2711 => PlatformSpec {
+            cross_prefix: "aarch64-linux-gnu-",
+            arm_cpu: Some("cortex-a72"),
+            cmake_extra: &[
+                "-DKernelArmExportPCNTUser=ON",
+                "-DKernelArmExportPTMRUser=ON",
+                "-DKernelArch=arm",
+                "-DKernelPlatform=bcm2711",
+                "-DKernelSel4Arch=aarch64",
+            ],
+            rel4_cmake_extra: &[
+                "-DKernelArmExportPCNTUser=ON",
+                "-DKernelArmExportPTMRUser=ON",
+                "-C", "./kernel-settings-aarch64.cmake",
+                "-DKernelPlatform=bcm2711",
+            ],
+            bin_target: "aarch64-unknown-none-softfloat",
+            loader_target: "aarch64-unknown-none",
+            xtask_args: &["--platform", "bcm2711", "--arm-pcnt", "--arm-ptmr"],
+            libsel4_include_names: ("arm", "aarch64", "bcm2711", "64"),
+            qemu_system: "qemu-system-aarch64",
+            qemu_machine: "raspi4b",
+            qemu_cpu: "cortex-a72",
+        },
+        Platform::Odroidc2 => PlatformSpec {
+            cross_prefix: "aarch64-linux-gnu-",
+            arm_cpu: Some("cortex-a53"),
+            cmake_extra: &[
+                "-DKernelArmExportPCNTUser=ON",
+                "-DKernelArmExportPTMRUser=ON",
+                "-DKernelArch=arm",
+                "-DKernelPlatform=odroidc2",
+                "-DKernelSel4Arch=aarch64",
+            ],
+            rel4_cmake_extra: &[
+                "-DKernelArmExportPCNTUser=ON",
+                "-DKernelArmExportPTMRUser=ON",
+                "-C", "./kernel-settings-aarch64.cmake",
+                "-DKernelPlatform=odroidc2",
+            ],
+            bin_target: "aarch64-unknown-none-softfloat",
+            loader_target: "aarch64-unknown-none",
+            xtask_args: &["--platform", "odroidc2", "--arm-pcnt", "--arm-ptmr"],
+            libsel4_include_names: ("arm", "aarch64", "odroidc2", "64"),
+            qemu_system: "",
+            qemu_machine: "",
+            qemu_cpu: "",
+        },
+        Platform::X86_64 => PlatformSpec {
+            cross_prefix: "",
+            arm_cpu: None,
+            cmake_extra: &["-DKernelArch=x86", "-DKernelPlatform=pc99", "-DKernelSel4Arch=x86_64"],
+            // seL4_c_impl ships no x86_64 kernel settings file, the platform is selected directly
+            rel4_cmake_extra: &["-DKernelArch=x86", "-DKernelPlatform=pc99", "-DKernelSel4Arch=x86_64"],
+            bin_target: "x86_64-unknown-none",
+            loader_target: "x86_64-unknown-none",
+            xtask_args: &["--platform", "x86_64"],
+            libsel4_include_names: ("x86", "x86_64", "pc99", "64"),
+            qemu_system: "qemu-system-x86_64",
+            qemu_machine: "q35",
+            qemu_cpu: "Nehalem,-vme,+pdpe1gb,-xsave,-xsaveopt,-xsavec,-fsgsbase,-invpcid,enforce",
+        },
+    }
+}
+
+impl Platform {
+    /// The name the platform is given on the command line
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Platform::Spike => "spike",
+            Platform::Spike32 => "spike32",
+            Platform::QemuArmVirt => "qemu-arm-virt",
+            Platform::Bcm2711 => "bcm2711",
+            Platform::Odroidc2 => "odroidc2",
+            Platform::X86_64 => "x86_64",
+        }
+    }
+
+    /// Whether the platform is an ARM one, which needs an ARM CPU model
+    pub(crate) fn is_arm(self) -> bool {
+        platform_spec(self).arm_cpu.is_some()
+    }
+
+    /// The target triple of the platform built with the RISC-V extensions, none when the
+    /// platform is not RISC-V or Rust has no bare metal target for the combination
+    pub(crate) fn riscv_target(self, ext: RiscvExt) -> Option<&'static str> {
+        match (self, ext) {
+            (Platform::Spike, RiscvExt::Imac) => Some("riscv64imac-unknown-none-elf"),
+            (Platform::Spike, RiscvExt::Gc) => Some("riscv64gc-unknown-none-elf"),
+            (Platform::Spike32, RiscvExt::Imac) => Some("riscv32imac-unknown-none-elf"),
+            (Platform::Spike32, RiscvExt::Imafc) => Some("riscv32imafc-unknown-none-elf"),
+            _ => None,
+        }
+    }
+
+    /// The C compiler used to build the kernel for the platform
+    pub(crate) fn cross_compiler(self) -> String {
+        format!("{}gcc", platform_spec(self).cross_prefix)
+    }
+}
+
+/// Print every platform `--platform` takes with the arch, cross compiler prefix and default
+/// ARM CPU of its spec
+pub(crate) fn list_platforms() {
+    println!("{:<16}{:<10}{:<28}default cpu", "platform", "arch", "cross prefix");
+    for platform in <Platform as clap::ValueEnum>::value_variants() {
+        let spec = platform_spec(*platform);
+        let (_, sel4_arch, _, _) = spec.libsel4_include_names;
+        let cross_prefix = if spec.cross_prefix.is_empty() { "(host)" } else { spec.cross_prefix };
+        println!("{:<16}{:<10}{:<28}{}", platform.name(), sel4_arch, cross_prefix, spec.arm_cpu.unwrap_or("-"));
+    }
+}
+
+impl std::fmt::Display for Platform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
@@ -0,0 +1,138 @@
+use clap::Parser;
+use std::path::PathBuf;
+use std::process::Command;
+
+use crate::error::InstallError;
+use crate::exec;
+use crate::install::tool_available;
+use crate::manifest::Manifest;
+use crate::platform::{platform_spec, Platform};
+
+#[derive(Debug, Parser)]
+pub(crate) struct RunOptions {
+    /// The platform the kernel was installed for
+    #[clap(default_value = "qemu-arm-virt", short, long)]
+    pub platform: Platform,
+    /// The image to boot, e.g. a kernel loader image with its payload [default: the `--with-payload`
+    /// image of the install, else its kernel ELF]
+    #[clap(long)]
+    pub image: Option<PathBuf>,
+    /// Memory of the machine, in MiB or with an `M` or `G` suffix like QEMU's `-m`, e.g. `2G`
+    #[clap(long, visible_alias = "mem", default_value = "1024", value_parser = parse_memory)]
+    pub memory: u32,
+    /// Number of CPUs of the machine, QEMU's default when unset, e.g. for kernels installed with `--smp`
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub smp: Option<u32>,
+    /// Extra arg appended to the QEMU command, e.g. `--extra-qemu-arg=-d --extra-qemu-arg=int`, can be repeated
+    #[clap(long, value_name = "ARG", allow_hyphen_values = true)]
+    pub extra_qemu_arg: Vec<String>,
+    /// Wait for gdb on port 1234 before booting, QEMU's `-s -S`, printing how to connect
+    #[clap(long)]
+    pub gdb: bool,
+    /// Print the QEMU command instead of running it, e.g. to paste into a script
+    #[clap(long)]
+    pub print_command: bool,
+}
+
+/// Boot the installed kernel in QEMU with its serial on stdio, the image is found from the install manifest
+pub(crate) fn run(opts: RunOptions, prefix: &str) -> anyhow::Result<()> {
+    let spec = platform_spec(opts.platform);
+    if spec.qemu_system.is_empty() {
+        return Err(anyhow::anyhow!("QEMU can not emulate platform {}, boot it on the board", opts.platform));
+    }
+    // QEMU's multiboot `-kernel` only loads 32-bit ELFs, the x86_64 kernel has to be converted
+    if opts.platform == Platform::X86_64 && opts.image.is_none() {
+        return Err(anyhow::anyhow!("QEMU can not boot the 64-bit x86_64 kernel ELF directly, convert it with `objcopy -O elf32-i386` and pass the copy with --image"));
+    }
+    let (prefix, manifest) = installed(prefix, opts.platform)?;
+    let prefix = prefix.as_str();
+    // The loader image of `install --with-payload` boots the kernel with its rootserver,
+    // the kernel ELF is the one installed under `--kernel-name`
+    let image = opts.image.clone()
+        .or_else(|| manifest.as_ref().and_then(|manifest| manifest.image.clone().or_else(|| manifest.kernel_elf.clone())))
+        .unwrap_or_else(|| PathBuf::from(prefix).join("bin/kernel.elf"));
+    if !exec::dry_run() && !opts.print_command {
+        if !image.exists() {
+            return Err(anyhow::anyhow!("{} does not exist, install the kernel first", image.display()));
+        }
+        if !tool_available(spec.qemu_system) {
+            return Err(InstallError::ToolMissing(spec.qemu_system.to_string()).into());
+        }
+    }
+
+    let mut command = Command::new(spec.qemu_system);
+    command
+        .args(["-machine", spec.qemu_machine, "-cpu", spec.qemu_cpu])
+        .arg("-m").arg(opts.memory.to_string())
+        .args(["-nographic", "-serial", "mon:stdio"])
+        .arg("-kernel").arg(&image);
+    if let Some(smp) = opts.smp {
+        command.arg("-smp").arg(smp.to_string());
+    }
+    if opts.gdb {
+        command.args(["-s", "-S"]);
+    }
+    command.args(&opts.extra_qemu_arg);
+    if opts.print_command {
+        println!("{}", exec::display(&command));
+        return Ok(());
+    }
+
+    if opts.gdb {
+        // The image may be a loader with the kernel as payload, the symbols are in the kernel ELF
+        let elf = manifest.and_then(|manifest| manifest.kernel_elf).unwrap_or_else(|| image.clone());
+        exec::report("QEMU waits for gdb on port 1234, connect with:");
+        exec::report(format_args!("  gdb-multiarch {} -ex 'target remote :1234'", elf.display()));
+    }
+
+    let status = exec::status(&mut command, "qemu")?;
+    if !status.success() {
+        return Err(anyhow::anyhow!("QEMU exited with {}", status));
+    }
+    Ok(())
+}
+
+/// The `--memory` size in MiB
+fn parse_memory(value: &str) -> Result<u32, String> {
+    let (number, scale) = match value.strip_suffix(['G', 'g']) {
+        Some(number) => (number, 1024),
+        None => (value.strip_suffix(['M', 'm']).unwrap_or(value), 1),
+    };
+    let number: u32 = number.parse().map_err(|_| format!("`{}` is not a size like 512, 512M or 2G", value))?;
+    match number.checked_mul(scale) {
+        Some(0) | None => Err(format!("`{}` is out of range", value)),
+        Some(mib) => Ok(mib),
+    }
+}
+
+/// The prefix the kernel of the platform was installed into with its manifest: prefix itself, or
+/// `<prefix>/<platform>` when several platforms were installed at once
+fn installed(prefix: &str, platform: Platform) -> anyhow::Result<(String, Option<Manifest>)> {
+    let subprefix = format!("{}/{}", prefix.trim_end_matches('/'), platform);
+    for candidate in [prefix, subprefix.as_str()] {
+        if let Some(manifest) = Manifest::read(candidate)?.filter(|manifest| manifest.platform == platform.name()) {
+            return Ok((candidate.to_string(), Some(manifest)));
+        }
+    }
+    Ok((prefix.to_string(), None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_memory_takes_mib_and_suffixes() {
+        assert_eq!(parse_memory("512"), Ok(512));
+        assert_eq!(parse_memory("512M"), Ok(512));
+        assert_eq!(parse_memory("2G"), Ok(2048));
+        assert_eq!(parse_memory("1g"), Ok(1024));
+    }
+
+    #[test]
+    fn parse_memory_rejects_invalid_sizes() {
+        for value in ["", "G", "2T", "1.5G", "-1", "0", "4194304G"] {
+            assert!(parse_memory(value).is_err(), "{}", value);
+        }
+    }
+}
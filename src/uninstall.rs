@@ -0,0 +1,78 @@
+use clap::Parser;
+use std::path::{Path, PathBuf};
+
+use crate::exec;
+use crate::manifest::Manifest;
+
+#[derive(Debug, Parser)]
+pub(crate) struct UninstallOptions {
+    #[clap(subcommand)]
+    command: UninstallCommand,
+}
+
+#[derive(Debug, Parser)]
+enum UninstallCommand {
+    /// Remove the kernel, libseL4 and kernel loader installed by `install kernel`
+    #[command(about = "Remove the kernel, libseL4 and kernel loader")]
+    Kernel(UninstallKernelOptions),
+}
+
+pub(crate) fn uninstall(opts: UninstallOptions, prefix: &str) -> anyhow::Result<()> {
+    match opts.command {
+        UninstallCommand::Kernel(kernel_opts) => {
+            uninstall_kernel(&kernel_opts, prefix)?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Parser)]
+struct UninstallKernelOptions {
+    /// Fail if any of the installed files is missing
+    #[clap(long)]
+    pub strict: bool,
+    /// File name the kernel was installed as with `install kernel --kernel-name`
+    #[clap(long, value_name = "NAME", default_value = "kernel.elf")]
+    pub kernel_name: String,
+}
+
+/// Remove everything `install kernel` puts under prefix, including its manifest
+fn uninstall_kernel(opts: &UninstallKernelOptions, prefix: &str) -> anyhow::Result<()> {
+    if prefix.trim().is_empty() || Path::new(prefix) == Path::new("/") {
+        return Err(anyhow::anyhow!("Refusing to uninstall from prefix '{}'", prefix));
+    }
+
+    let manifest = Manifest::path(prefix);
+    let prefix = PathBuf::from(prefix);
+    let paths = [
+        prefix.join("bin").join(&opts.kernel_name),
+        prefix.join("bin/sel4-kernel-loader"),
+        prefix.join("bin/sel4-kernel-loader-add-payload"),
+        prefix.join("libsel4"),
+        prefix.join("support"),
+        manifest,
+    ];
+
+    for path in paths.iter() {
+        let result = if path.is_dir() {
+            exec::remove_dir_all(path)
+        } else if path.exists() {
+            exec::remove_file(path)
+        } else {
+            Err(std::io::ErrorKind::NotFound.into())
+        };
+        match result {
+            Ok(()) if exec::dry_run() => {}
+            Ok(()) => println!("Removed {}", path.display()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                if opts.strict {
+                    return Err(anyhow::anyhow!("{} is not installed", path.display()));
+                }
+                println!("Skipped {}, not installed", path.display());
+            }
+            Err(err) => return Err(anyhow::anyhow!("Failed to remove {}: {}", path.display(), err)),
+        }
+    }
+
+    Ok(())
+}
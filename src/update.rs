@@ -0,0 +1,65 @@
+use clap::Parser;
+use std::path::Path;
+use std::process::Command;
+
+use crate::error::InstallError;
+use crate::exec;
+use crate::install::{git_head, work_dir, REL4_KERNEL_DIR, SEL4_KERNEL_DIR};
+
+#[derive(Debug, Parser)]
+pub(crate) struct UpdateOptions {
+    /// rel4 kernel branch to update to
+    #[clap(long, default_value = "master")]
+    pub branch: String,
+    /// Local reL4 kernel path to update instead of the clone under `--tmp-dir`, needs `--reset-local`
+    #[clap(short = 'L', long)]
+    pub local: Option<String>,
+    /// Local seL4_c_impl path to update instead of the clone under `--tmp-dir`, needs `--reset-local`
+    #[clap(long)]
+    pub local_sel4: Option<String>,
+    /// Allow resetting the `--local` checkouts, discarding their local changes
+    #[clap(long)]
+    pub reset_local: bool,
+}
+
+/// Fetch the kernel sources cloned by `install kernel` and reset them to the remote
+/// head, without rebuilding anything
+pub(crate) fn update(opts: UpdateOptions, tmp_dir: &str) -> anyhow::Result<()> {
+    if (opts.local.is_some() || opts.local_sel4.is_some()) && !opts.reset_local {
+        return Err(InstallError::Usage("resetting local checkouts discards their changes, pass --reset-local".to_string()).into());
+    }
+
+    let rel4_dir = opts.local.clone().unwrap_or_else(|| work_dir(tmp_dir, REL4_KERNEL_DIR));
+    update_clone(&rel4_dir, &opts.branch)?;
+    let sel4_dir = match (&opts.local_sel4, &opts.local) {
+        (Some(local_sel4), _) => local_sel4.clone(),
+        (None, Some(local)) => format!("{}/../kernel", local),
+        (None, None) => work_dir(tmp_dir, SEL4_KERNEL_DIR),
+    };
+    // seL4_c_impl is cloned from its default branch
+    update_clone(&sel4_dir, "HEAD")?;
+    Ok(())
+}
+
+/// `git fetch origin <reference>` and hard reset the clone at dir to it
+fn update_clone(dir: &str, reference: &str) -> anyhow::Result<()> {
+    let path = Path::new(dir);
+    if !path.exists() {
+        println!("{} is not cloned, skipping", dir);
+        return Ok(());
+    }
+
+    let old = git_head(path);
+    let status = exec::status(Command::new("git").args(["fetch", "origin", reference]).current_dir(dir), "git")?;
+    if !status.success() {
+        return Err(InstallError::FetchFailed { reference: reference.to_string(), dir: dir.to_string() }.into());
+    }
+    let status = exec::status(Command::new("git").args(["reset", "--hard", "FETCH_HEAD"]).current_dir(dir), "git")?;
+    if !status.success() {
+        return Err(InstallError::ResetFailed { reference: reference.to_string(), dir: dir.to_string() }.into());
+    }
+
+    let unknown = || "unknown".to_string();
+    println!("{}: {} -> {}", dir, old.unwrap_or_else(unknown), git_head(path).unwrap_or_else(unknown));
+    Ok(())
+}
@@ -0,0 +1,49 @@
+#![cfg(unix)]
+
+use std::os::unix::fs::PermissionsExt;
+use std::process::Command;
+
+/// A build tool that prints to stdout, as cmake, ninja and cargo do, and creates the libseL4
+/// headers on `ninja install`
+const NOISY_TOOL: &str = "#!/bin/sh
+echo \"noise from $(basename \"$0\") $*\"
+case \"$*\" in *install*) mkdir -p \"$TEST_PREFIX/libsel4/include\";; esac
+";
+
+#[test]
+fn json_stdout_holds_only_the_report() {
+    let dir = std::env::temp_dir().join(format!("rel4-cli-json-{}", std::process::id()));
+    let bin = dir.join("bin");
+    let local = dir.join("src/rel4_kernel");
+    let prefix = dir.join("prefix");
+    std::fs::create_dir_all(&bin).unwrap();
+    std::fs::create_dir_all(local.join("xtask")).unwrap();
+    std::fs::create_dir_all(dir.join("src/kernel")).unwrap();
+    std::fs::write(local.join("Cargo.toml"), "").unwrap();
+    for tool in ["cmake", "ninja", "rustup"] {
+        let path = bin.join(tool);
+        std::fs::write(&path, NOISY_TOOL).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    let path = std::env::join_paths(std::iter::once(bin.clone()).chain(std::env::split_paths(&std::env::var_os("PATH").unwrap()))).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_rel4-cli"))
+        .args(["--format", "json", "-P"]).arg(&prefix)
+        .arg("--tmp-dir").arg(dir.join("tmp"))
+        .args(["install", "kernel", "--skip-tool-check", "--no-loader", "-L"]).arg(&local)
+        .env("PATH", path)
+        .env("TEST_PREFIX", &prefix)
+        .env_remove("REL4_PREFIX")
+        .env_remove("REL4_PLATFORM")
+        .current_dir(&dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let report: serde_json::Value = serde_json::from_str(&stdout).unwrap_or_else(|err| panic!("{}: {}", err, stdout));
+    assert_eq!(report["platform"], "qemu-arm-virt");
+    assert_eq!(report["prefix"], prefix.to_str().unwrap());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("noise from cmake"));
+    std::fs::remove_dir_all(&dir).unwrap();
+}